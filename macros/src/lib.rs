@@ -0,0 +1,531 @@
+//! `abort_safe_future`的过程宏
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::parse::{Parse, ParseStream};
+use syn::visit::Visit;
+use syn::{parse_macro_input, parse_quote, Block, Data, DeriveInput, Expr, FnArg, Fields, Index, ItemFn, ReturnType, Stmt};
+
+/// 把`async fn`改写成返回`impl AbortSafeFuture`的函数。
+///
+/// 目前只支持顺序执行的`.await`，且`.await`只能出现在以下位置：
+///
+/// * `let <pat> = <expr>.await;`
+/// * `<expr>.await;`
+/// * 函数体的最后一个表达式`<expr>.await`
+///
+/// 其中`<expr>`必须是`AbortSafeFuture`。每个`.await`之后的代码会被改写成`then`的闭包，
+/// 所以中断时由`Then`负责对当前正在执行的子future调用`poll_cancel`，已经绑定的局部变量随闭包一起析构。
+/// 函数体在第一次`poll`时才开始执行。
+///
+/// 局部变量只会被同步析构：它们被移动进`then`的闭包，中断时跟着闭包一起`drop`，
+/// 不会调用`AsyncDrop::poll_drop`，也不保证按照声明的逆序析构。
+/// 需要异步回收的值应该由被`.await`的future自己持有，在它的`poll_cancel`里回收。
+///
+/// 不支持：循环、分支中的`.await`，`return`，`?`，以及引用类型的参数（包括`&self`），
+/// 引用类型的参数会直接报错。
+///
+/// 下面的例子与手写的`then`链比较结果和回收顺序：
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use abort_safe_future::{abort_safe, pending, ready, AbortSafeFuture, AbortSafeFutureExt};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::testing::{poll_cancel_once, poll_once, DropOrderRecorder};
+///
+/// #[abort_safe]
+/// async fn add(recorder: DropOrderRecorder, a: u32, b: u32) -> u32 {
+///     let a = recorder.wrap("a", ready(a)).await;
+///     let b = recorder.wrap("b", ready(b)).await;
+///     a + b
+/// }
+///
+/// fn add_by_hand(recorder: DropOrderRecorder, a: u32, b: u32) -> impl AbortSafeFuture<Output = u32> {
+///     ready(()).then(move |()| {
+///         recorder.wrap("a", ready(a)).then(move |a| recorder.wrap("b", ready(b)).then(move |b| ready(a + b)))
+///     })
+/// }
+///
+/// #[abort_safe]
+/// async fn stuck(recorder: DropOrderRecorder) -> u32 {
+///     let a = recorder.wrap("a", ready(1)).await;
+///     let b = recorder.wrap("b", pending::<u32>()).await;
+///     a + b
+/// }
+///
+/// fn stuck_by_hand(recorder: DropOrderRecorder) -> impl AbortSafeFuture<Output = u32> {
+///     ready(()).then(move |()| {
+///         recorder.wrap("a", ready(1)).then(move |a| recorder.wrap("b", pending::<u32>()).then(move |b| ready(a + b)))
+///     })
+/// }
+///
+/// let (generated, by_hand) = (DropOrderRecorder::new(), DropOrderRecorder::new());
+/// assert_eq!(block_on(add(generated.clone(), 1, 2)), block_on(add_by_hand(by_hand.clone(), 1, 2)));
+/// assert_eq!(generated.events(), ["a", "b"]);
+/// assert_eq!(generated.events(), by_hand.events());
+///
+/// fn cancel_mid_step(fut: impl AbortSafeFuture<Output = u32>, recorder: &DropOrderRecorder) -> Vec<Vec<&'static str>> {
+///     let mut fut = Box::pin(ManuallyDrop::new(fut));
+///     assert!(poll_once(fut.as_mut()).is_pending());
+///     let before = recorder.events();
+///     assert!(poll_cancel_once(fut.as_mut()).is_ready());
+///     vec![before, recorder.events()]
+/// }
+///
+/// let (generated, by_hand) = (DropOrderRecorder::new(), DropOrderRecorder::new());
+/// let events = cancel_mid_step(stuck(generated.clone()), &generated);
+/// assert_eq!(events, [vec!["a"], vec!["a", "b"]]);
+/// assert_eq!(events, cancel_mid_step(stuck_by_hand(by_hand.clone()), &by_hand));
+/// ```
+///
+/// ```compile_fail
+/// use abort_safe_future::{abort_safe, ready};
+///
+/// #[abort_safe]
+/// async fn borrow(x: &u32) -> u32 {
+///     let x = ready(*x).await;
+///     x
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn abort_safe(args: TokenStream, input: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        return syn::Error::new(Span::call_site(), "`#[abort_safe]` does not take arguments")
+            .to_compile_error()
+            .into();
+    }
+
+    let item = parse_macro_input!(input as ItemFn);
+    match expand(item) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// 用顺序执行的语句构造`AbortSafeStream`。
+///
+/// 语句的限制与`#[abort_safe]`相同，另外可以用`yield_item(<expr>).await;`产生一个元素。
+/// 每段语句在上一个元素被取走之后，下一次`poll_next`时才开始执行。
+/// 改写的结果由`then`、`FlattenStream`和`Chain`组成，所以中断时正在执行的future会被取消。
+///
+/// 下面的例子与手写的三个元素的stream比较结果，并在某一段语句执行到一半时取消：
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use std::task::{Context, Poll};
+/// use abort_safe_future::{abort_safe_stream, pending, ready, AbortSafeFutureExt, AbortSafeStream, AbortSafeStreamExt};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::testing::{noop_waker, DropOrderRecorder};
+///
+/// let generated = abort_safe_stream! {
+///     let a = ready(1).await;
+///     yield_item(a).await;
+///     let b = ready(a + 1).await;
+///     yield_item(b).await;
+///     yield_item(b + 1).await;
+/// };
+/// let by_hand = ready(1).into_stream().chain(ready(2).into_stream()).chain(ready(3).into_stream());
+/// let generated: Vec<u32> = block_on(generated.collect());
+/// assert_eq!(generated, [1, 2, 3]);
+/// assert_eq!(generated, block_on(by_hand.collect::<Vec<u32>>()));
+///
+/// let recorder = DropOrderRecorder::new();
+/// let step = recorder.clone();
+/// let stream = abort_safe_stream! {
+///     yield_item(1u32).await;
+///     let b = step.wrap("step", pending::<u32>()).await;
+///     yield_item(b).await;
+/// };
+/// let mut stream = Box::pin(ManuallyDrop::new(stream));
+/// let waker = noop_waker();
+/// let mut cx = Context::from_waker(&waker);
+/// assert_eq!(stream.as_mut().poll_next(&mut cx), Poll::Ready(Some(1)));
+/// assert_eq!(stream.as_mut().poll_next(&mut cx), Poll::Pending);
+/// assert!(recorder.events().is_empty());
+/// assert_eq!(stream.as_mut().poll_cancel(&mut cx), Poll::Ready(()));
+/// assert_eq!(recorder.events(), ["step"]);
+/// ```
+#[proc_macro]
+pub fn abort_safe_stream(input: TokenStream) -> TokenStream {
+    let body = parse_macro_input!(input as StreamBody);
+    match expand_stream(&body.stmts) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// 为所有字段都实现了`AsyncDrop`的结构体实现`AsyncDrop`。
+///
+/// 生成的`poll_drop`按照字段声明的逆序依次调用每个字段的`poll_drop`，
+/// 某个字段返回`Poll::Pending`时直接返回`Poll::Pending`，下次调用时从头开始，
+/// 已经完成的字段会再次返回`Poll::Ready`。
+///
+/// derive宏不能给输入的结构体加上`#[pin_project]`，所以字段的投影由`async_drop::poll_drop_field`完成，
+/// 与`pin_manually_drop_as_mut`一样依赖结构体已经被`Pin<&mut ManuallyDrop<_>>`保护。
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use std::pin::Pin;
+/// use std::task::{Context, Poll};
+/// use abort_safe_future::AsyncDrop;
+/// use abort_safe_future::testing::{noop_waker, DropOrderRecorder};
+///
+/// // needs two calls to finish
+/// struct Named {
+///     name: &'static str,
+///     recorder: DropOrderRecorder,
+///     polls: usize,
+/// }
+///
+/// impl AsyncDrop for Named {
+///     fn poll_drop(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+///         self.polls += 1;
+///         match self.polls {
+///             1 => Poll::Pending,
+///             2 => {
+///                 self.recorder.record(self.name);
+///                 Poll::Ready(())
+///             }
+///             _ => Poll::Ready(()),
+///         }
+///     }
+/// }
+///
+/// #[derive(AsyncDrop)]
+/// struct Pair {
+///     first: Named,
+///     second: Named,
+/// }
+///
+/// #[derive(AsyncDrop)]
+/// struct Wrapper<T>(T, Named);
+///
+/// let recorder = DropOrderRecorder::new();
+/// let named = |name| Named { name, recorder: recorder.clone(), polls: 0 };
+/// let mut value = ManuallyDrop::new(Wrapper(Pair { first: named("first"), second: named("second") }, named("last")));
+///
+/// let waker = noop_waker();
+/// let mut cx = Context::from_waker(&waker);
+/// let mut polls = 0;
+/// while Pin::new(&mut value).poll_drop(&mut cx).is_pending() {
+///     polls += 1;
+/// }
+/// assert_eq!(polls, 3);
+/// assert_eq!(recorder.events(), ["last", "second", "first"]);
+/// ```
+///
+/// ```compile_fail
+/// #[derive(abort_safe_future::AsyncDrop)]
+/// enum NotAStruct {
+///     A,
+/// }
+/// ```
+#[proc_macro_derive(AsyncDrop)]
+pub fn derive_async_drop(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand_async_drop(input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand(mut item: ItemFn) -> syn::Result<TokenStream2> {
+    if item.sig.asyncness.is_none() {
+        return Err(syn::Error::new_spanned(
+            item.sig.fn_token,
+            "`#[abort_safe]` can only be applied to `async fn`",
+        ));
+    }
+
+    for input in &item.sig.inputs {
+        check_not_reference(input)?;
+    }
+
+    let output = match &item.sig.output {
+        ReturnType::Default => quote!(()),
+        ReturnType::Type(_, ty) => quote!(#ty),
+    };
+
+    let body = expand_stmts(&item.block.stmts)?;
+
+    item.sig.asyncness = None;
+    item.sig.output = parse_quote!(-> impl ::abort_safe_future::AbortSafeFuture<Output = #output>);
+    item.block = parse_quote!({
+        ::abort_safe_future::AbortSafeFutureExt::then(
+            ::abort_safe_future::ready(()),
+            move |()| #body,
+        )
+    });
+
+    Ok(quote!(#item))
+}
+
+/// `abort_safe_stream!`的输入，不带大括号的语句序列
+struct StreamBody {
+    stmts: Vec<Stmt>,
+}
+
+impl Parse for StreamBody {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        Ok(Self {
+            stmts: Block::parse_within(input)?,
+        })
+    }
+}
+
+/// 在第一个`yield_item(<expr>).await;`处切开，前面的语句和元素组成一个future，
+/// 它的结果是这个元素和剩下的语句组成的stream
+fn expand_stream(stmts: &[Stmt]) -> syn::Result<TokenStream2> {
+    let split = stmts.iter().enumerate().find_map(|(i, stmt)| yield_item(stmt).map(|item| (i, item)));
+
+    let tail = match split {
+        Some((i, item)) => {
+            let rest = expand_stream(&stmts[i + 1..])?;
+            let prefix = &stmts[..i];
+            expand_stmts(&[
+                prefix,
+                &[Stmt::Expr(parse_quote!({
+                    let item = #item;
+                    ::abort_safe_future::AbortSafeStreamExt::chain(
+                        ::abort_safe_future::stream::IntoStream::new(::abort_safe_future::ready(item)),
+                        #rest,
+                    )
+                }))],
+            ]
+            .concat())?
+        }
+        None => {
+            let mut stmts = stmts.to_vec();
+            stmts.push(Stmt::Expr(parse_quote!(::abort_safe_future::stream::empty())));
+            expand_stmts(&stmts)?
+        }
+    };
+
+    Ok(quote!(
+        ::abort_safe_future::AbortSafeFutureExt::flatten_stream(
+            ::abort_safe_future::AbortSafeFutureExt::then(
+                ::abort_safe_future::ready(()),
+                move |()| #tail,
+            )
+        )
+    ))
+}
+
+/// `yield_item(<expr>).await;`中的`<expr>`
+fn yield_item(stmt: &Stmt) -> Option<&Expr> {
+    let await_expr = match stmt {
+        Stmt::Semi(Expr::Await(await_expr), _) => await_expr,
+        _ => return None,
+    };
+
+    match &*await_expr.base {
+        Expr::Call(call) if call.args.len() == 1 => match &*call.func {
+            Expr::Path(path) if path.qself.is_none() && path.path.is_ident("yield_item") => call.args.first(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn expand_async_drop(mut input: DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "`#[derive(AsyncDrop)]` only supports structs",
+            ))
+        }
+    };
+
+    let (members, types): (Vec<TokenStream2>, Vec<&syn::Type>) = match fields {
+        Fields::Named(named) => named
+            .named
+            .iter()
+            .map(|field| {
+                let ident = &field.ident;
+                (quote!(#ident), &field.ty)
+            })
+            .unzip(),
+        Fields::Unnamed(unnamed) => unnamed
+            .unnamed
+            .iter()
+            .enumerate()
+            .map(|(i, field)| {
+                let index = Index::from(i);
+                (quote!(#index), &field.ty)
+            })
+            .unzip(),
+        Fields::Unit => (Vec::new(), Vec::new()),
+    };
+
+    let where_clause = input.generics.make_where_clause();
+    for ty in &types {
+        where_clause
+            .predicates
+            .push(parse_quote!(#ty: ::abort_safe_future::AsyncDrop));
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let members = members.iter().rev();
+
+    Ok(quote! {
+        impl #impl_generics ::abort_safe_future::AsyncDrop for #name #ty_generics #where_clause {
+            fn poll_drop(
+                mut self: ::core::pin::Pin<&mut ::core::mem::ManuallyDrop<Self>>,
+                cx: &mut ::core::task::Context<'_>,
+            ) -> ::core::task::Poll<()> {
+                // fields are never moved out of `self`
+                let this = unsafe { ::core::pin::Pin::get_unchecked_mut(::core::pin::Pin::as_mut(&mut self)) };
+                #(
+                    if unsafe { ::abort_safe_future::async_drop::poll_drop_field(&mut this.#members, cx) }.is_pending() {
+                        return ::core::task::Poll::Pending;
+                    }
+                )*
+                ::core::task::Poll::Ready(())
+            }
+        }
+    })
+}
+
+/// 在第一个`.await`处切开，剩下的语句放进`then`的闭包里。
+/// 已经绑定的局部变量被`move`进闭包，只有同步的`Drop`，不会经过`AsyncDrop`
+fn expand_stmts(stmts: &[Stmt]) -> syn::Result<TokenStream2> {
+    for (i, stmt) in stmts.iter().enumerate() {
+        let prefix = &stmts[..i];
+        let rest = &stmts[i + 1..];
+
+        match stmt {
+            Stmt::Local(local) => {
+                if let Some((_, init)) = &local.init {
+                    if let Expr::Await(await_expr) = &**init {
+                        check_unsupported(&await_expr.base)?;
+                        let base = &await_expr.base;
+                        let pat = &local.pat;
+                        let rest = expand_stmts(rest)?;
+                        return Ok(quote!({
+                            #(#prefix)*
+                            ::abort_safe_future::AbortSafeFutureExt::then(#base, move |#pat| #rest)
+                        }));
+                    }
+                }
+                check_unsupported_stmt(stmt)?;
+            }
+            Stmt::Semi(Expr::Await(await_expr), _) => {
+                check_unsupported(&await_expr.base)?;
+                let base = &await_expr.base;
+                let rest = expand_stmts(rest)?;
+                return Ok(quote!({
+                    #(#prefix)*
+                    ::abort_safe_future::AbortSafeFutureExt::then(#base, move |_| #rest)
+                }));
+            }
+            Stmt::Expr(Expr::Await(await_expr)) if rest.is_empty() => {
+                check_unsupported(&await_expr.base)?;
+                let base = &await_expr.base;
+                return Ok(quote!({
+                    #(#prefix)*
+                    #base
+                }));
+            }
+            _ => check_unsupported_stmt(stmt)?,
+        }
+    }
+
+    match stmts.split_last() {
+        Some((Stmt::Expr(tail), prefix)) => Ok(quote!({
+            #(#prefix)*
+            ::abort_safe_future::ready(#tail)
+        })),
+        _ => Ok(quote!({
+            #(#stmts)*
+            ::abort_safe_future::ready(())
+        })),
+    }
+}
+
+/// the body runs after the function returns, so borrowed arguments can't be captured
+fn check_not_reference(input: &FnArg) -> syn::Result<()> {
+    let ty = match input {
+        FnArg::Receiver(receiver) if receiver.reference.is_some() => {
+            return Err(syn::Error::new_spanned(receiver, "`#[abort_safe]` does not support `&self` or `&mut self`"));
+        }
+        FnArg::Receiver(_) => return Ok(()),
+        FnArg::Typed(pat_type) => &pat_type.ty,
+    };
+
+    let mut visitor = References { error: None };
+    visitor.visit_type(ty);
+    visitor.error.map_or(Ok(()), Err)
+}
+
+/// 找出参数类型中的引用
+struct References {
+    error: Option<syn::Error>,
+}
+
+impl<'ast> Visit<'ast> for References {
+    fn visit_type_reference(&mut self, node: &'ast syn::TypeReference) {
+        if self.error.is_none() {
+            self.error = Some(syn::Error::new_spanned(
+                node,
+                "`#[abort_safe]` does not support reference-typed parameters",
+            ));
+        }
+    }
+}
+
+fn check_unsupported(expr: &Expr) -> syn::Result<()> {
+    let mut visitor = Unsupported { error: None };
+    visitor.visit_expr(expr);
+    visitor.error.map_or(Ok(()), Err)
+}
+
+fn check_unsupported_stmt(stmt: &Stmt) -> syn::Result<()> {
+    let mut visitor = Unsupported { error: None };
+    visitor.visit_stmt(stmt);
+    visitor.error.map_or(Ok(()), Err)
+}
+
+/// 找出不能改写成`then`链的表达式
+struct Unsupported {
+    error: Option<syn::Error>,
+}
+
+impl Unsupported {
+    fn push(&mut self, error: syn::Error) {
+        match &mut self.error {
+            Some(existing) => existing.combine(error),
+            None => self.error = Some(error),
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for Unsupported {
+    fn visit_expr_await(&mut self, node: &'ast syn::ExprAwait) {
+        self.push(syn::Error::new_spanned(
+            node,
+            "`#[abort_safe]` only supports `.await` in `let <pat> = <expr>.await;`, `<expr>.await;` or the tail expression",
+        ));
+    }
+
+    fn visit_expr_return(&mut self, node: &'ast syn::ExprReturn) {
+        self.push(syn::Error::new_spanned(node, "`return` is not supported in `#[abort_safe]`"));
+    }
+
+    fn visit_expr_try(&mut self, node: &'ast syn::ExprTry) {
+        self.push(syn::Error::new_spanned(node, "`?` is not supported in `#[abort_safe]`"));
+    }
+
+    // `.await`, `return` and `?` inside these belong to another body
+    fn visit_expr_async(&mut self, _node: &'ast syn::ExprAsync) {}
+
+    fn visit_expr_closure(&mut self, _node: &'ast syn::ExprClosure) {}
+
+    fn visit_item(&mut self, _node: &'ast syn::Item) {}
+}
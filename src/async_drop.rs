@@ -0,0 +1,421 @@
+use core::task::Context;
+use core::task::Poll;
+use core::pin::Pin;
+use core::mem::ManuallyDrop;
+use core::ptr;
+use core::ops::{Deref, DerefMut};
+
+use std::sync::{Arc, Mutex, TryLockError};
+use std::task::ready;
+use pin_project::pin_project;
+use crate::future::AbortSafeFuture;
+use crate::helpers::pin_manually_drop_as_mut;
+
+
+/// 需要异步回收资源的类型，类似于`AbortSafeFuture::poll_cancel`，但不需要是一个future。
+///
+/// 持有`AsyncDrop`值的future，需要在完成或者被取消时调用其`poll_drop`，直到其返回`Poll::Ready`。否则可能内存泄漏。
+pub trait AsyncDrop {
+    /// 回收资源。
+    /// 返回`Poll::Ready`之后再调用此方法，应该直接返回`Poll::Ready`，且没有其他副作用。
+    fn poll_drop(self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()>;
+}
+
+/// `#[derive(AsyncDrop)]`生成的代码使用
+///
+/// # Safety
+///
+/// `field`必须是被`Pin<&mut ManuallyDrop<_>>`保护的结构体的字段，且之后不会被移动。
+#[doc(hidden)]
+pub unsafe fn poll_drop_field<T: AsyncDrop>(field: &mut T, cx: &mut Context<'_>) -> Poll<()> {
+    // `ManuallyDrop<T>` is `repr(transparent)`
+    let field = Pin::new_unchecked(&mut *(field as *mut T as *mut ManuallyDrop<T>));
+    field.poll_drop(cx)
+}
+
+/// 只需要同步析构的类型，在`poll_drop`中直接析构`T`
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::cell::Cell;
+/// use std::mem::ManuallyDrop;
+/// use std::pin::Pin;
+/// use std::task::Context;
+/// use abort_safe_future::{AsyncDrop, SyncDrop};
+/// use abort_safe_future::testing::noop_waker;
+///
+/// struct TestDrop<'a>(&'a Cell<u32>);
+///
+/// impl Drop for TestDrop<'_> {
+///     fn drop(&mut self) {
+///         self.0.set(self.0.get() + 1);
+///     }
+/// }
+///
+/// let drops = Cell::new(0);
+/// let mut guard = ManuallyDrop::new(SyncDrop::new(TestDrop(&drops)));
+/// let waker = noop_waker();
+/// assert!(Pin::new(&mut guard).poll_drop(&mut Context::from_waker(&waker)).is_ready());
+/// assert_eq!(drops.get(), 1);
+/// ```
+pub struct SyncDrop<T> {
+    value: Option<T>,
+}
+
+impl<T> SyncDrop<T> {
+    pub fn new(value: T) -> Self {
+        Self { value: Some(value) }
+    }
+}
+
+impl<T> Deref for SyncDrop<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.value.as_ref().expect("SyncDrop accessed after poll_drop")
+    }
+}
+
+impl<T> DerefMut for SyncDrop<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.value.as_mut().expect("SyncDrop accessed after poll_drop")
+    }
+}
+
+impl<T> AsyncDrop for SyncDrop<T> {
+    fn poll_drop(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+        // `T` is never pinned
+        let this = unsafe { pin_manually_drop_as_mut(&mut self).get_unchecked_mut() };
+        this.value = None;
+        Poll::Ready(())
+    }
+}
+
+/// 从池中借出的值。`poll_drop`时把值还回池中，池已经满了的话直接析构。
+///
+/// 归还时只用`try_lock`，池的锁被占用时唤醒自己并返回`Poll::Pending`，不会阻塞执行器。
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use std::pin::Pin;
+/// use std::rc::Rc;
+/// use std::sync::{Arc, Mutex};
+/// use std::task::{Context, Poll};
+/// use abort_safe_future::AsyncDrop;
+/// use abort_safe_future::async_drop::Pooled;
+/// use abort_safe_future::testing::noop_waker;
+///
+/// let pool = Arc::new(Mutex::new(Vec::new()));
+/// let conn = Rc::new(());
+///
+/// let waker = noop_waker();
+/// let mut cx = Context::from_waker(&waker);
+/// for _ in 0..2 {
+///     let mut pooled = ManuallyDrop::new(Pooled::new(conn.clone(), pool.clone(), 1));
+///     assert_eq!(Pin::new(&mut pooled).poll_drop(&mut cx), Poll::Ready(()));
+/// }
+///
+/// // one is back in the pool, the other has been dropped
+/// assert_eq!(pool.lock().unwrap().len(), 1);
+/// assert_eq!(Rc::strong_count(&conn), 2);
+/// // neither `Pooled` holds on to the pool any more
+/// assert_eq!(Arc::strong_count(&pool), 1);
+/// ```
+pub struct Pooled<T> {
+    value: Option<T>,
+    pool: Option<Arc<Mutex<Vec<T>>>>,
+    capacity: usize,
+}
+
+impl<T> Pooled<T> {
+    pub fn new(value: T, pool: Arc<Mutex<Vec<T>>>, capacity: usize) -> Self {
+        Self {
+            value: Some(value),
+            pool: Some(pool),
+            capacity,
+        }
+    }
+}
+
+impl<T> Unpin for Pooled<T> {}
+
+impl<T> Deref for Pooled<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.value.as_ref().expect("Pooled accessed after poll_drop")
+    }
+}
+
+impl<T> DerefMut for Pooled<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.value.as_mut().expect("Pooled accessed after poll_drop")
+    }
+}
+
+impl<T> AsyncDrop for Pooled<T> {
+    fn poll_drop(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).get_mut();
+        let value = match this.value.take() {
+            Some(value) => value,
+            None => return Poll::Ready(()),
+        };
+
+        if let Some(pool) = this.pool.as_ref() {
+            let mut pool = match pool.try_lock() {
+                Ok(pool) => pool,
+                Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+                Err(TryLockError::WouldBlock) => {
+                    this.value = Some(value);
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+            };
+
+            if pool.len() < this.capacity {
+                pool.push(value);
+            } else {
+                // the pool is full
+                drop(pool);
+                drop(value);
+            }
+        }
+
+        // release the pool as well, otherwise it leaks together with `ManuallyDrop<Self>`
+        this.pool = None;
+        Poll::Ready(())
+    }
+}
+
+pub fn defer_async<F, Fut>(f: F) -> AsyncScopeGuard<F, Fut>
+where
+    F: FnOnce() -> Fut,
+    Fut: AbortSafeFuture<Output = ()>,
+{
+    AsyncScopeGuard::new(f)
+}
+
+/// 在`poll_drop`中执行一个异步的清理操作。
+///
+/// 第一次调用`poll_drop`时才会调用`f`构造清理的future，之后的调用会继续驱动同一个future直到完成。
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::cell::Cell;
+/// use std::mem::ManuallyDrop;
+/// use std::pin::Pin;
+/// use std::task::{Context, Poll};
+/// use abort_safe_future::{defer_async, poll_fn, AsyncDrop};
+/// use abort_safe_future::testing::noop_waker;
+///
+/// let built = Cell::new(0);
+/// let ran = Cell::new(false);
+/// let mut guard = Box::pin(ManuallyDrop::new(defer_async(|| {
+///     built.set(built.get() + 1);
+///     let ran = &ran;
+///     let mut yielded = false;
+///     poll_fn(move |_| {
+///         if !yielded {
+///             yielded = true;
+///             return Poll::Pending;
+///         }
+///         ran.set(true);
+///         Poll::Ready(())
+///     })
+/// })));
+///
+/// let waker = noop_waker();
+/// let mut cx = Context::from_waker(&waker);
+/// assert!(guard.as_mut().poll_drop(&mut cx).is_pending());
+/// assert!(guard.as_mut().poll_drop(&mut cx).is_ready());
+/// assert!(ran.get());
+/// assert_eq!(built.get(), 1);
+/// ```
+#[pin_project]
+pub struct AsyncScopeGuard<F, Fut> {
+    f: Option<F>,
+    #[pin]
+    cleanup: Option<ManuallyDrop<Fut>>,
+}
+
+impl<F, Fut> AsyncScopeGuard<F, Fut> {
+    pub fn new(f: F) -> Self {
+        Self {
+            f: Some(f),
+            cleanup: None,
+        }
+    }
+}
+
+impl<F, Fut> AsyncDrop for AsyncScopeGuard<F, Fut>
+where
+    F: FnOnce() -> Fut,
+    Fut: AbortSafeFuture<Output = ()>,
+{
+    fn poll_drop(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(f) = this.f.take() {
+            this.cleanup.set(Some(ManuallyDrop::new(f())));
+        }
+
+        if let Some(cleanup) = this.cleanup.as_mut().as_pin_mut() {
+            ready!(cleanup.poll(cx));
+            this.cleanup.set(None);
+        }
+
+        Poll::Ready(())
+    }
+}
+
+/// `Some`时回收inner，完成后变为`None`
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use std::pin::Pin;
+/// use std::rc::Rc;
+/// use std::task::Context;
+/// use abort_safe_future::{AsyncDrop, SyncDrop};
+/// use abort_safe_future::testing::noop_waker;
+///
+/// let waker = noop_waker();
+/// let mut cx = Context::from_waker(&waker);
+///
+/// let resource = Rc::new(());
+/// let mut some = ManuallyDrop::new(Some(SyncDrop::new(resource.clone())));
+/// assert!(Pin::new(&mut some).poll_drop(&mut cx).is_ready());
+/// assert!(some.is_none());
+/// assert_eq!(Rc::strong_count(&resource), 1);
+///
+/// let mut none = ManuallyDrop::new(None::<SyncDrop<Rc<()>>>);
+/// assert!(Pin::new(&mut none).poll_drop(&mut cx).is_ready());
+/// ```
+impl<T: AsyncDrop> AsyncDrop for Option<T> {
+    fn poll_drop(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = unsafe { pin_manually_drop_as_mut(&mut self).get_unchecked_mut() };
+        if let Some(inner) = this {
+            ready!(unsafe { poll_drop_field(inner, cx) });
+            // inner has been dropped asynchronously, don't run its destructor again
+            unsafe { ptr::write(this, None) };
+        }
+
+        Poll::Ready(())
+    }
+}
+
+/// 从最后一个元素开始依次回收，已经回收的元素会被移出`Vec`，所以可以在`Poll::Pending`之后继续
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::cell::RefCell;
+/// use std::mem::ManuallyDrop;
+/// use std::pin::Pin;
+/// use std::rc::Rc;
+/// use std::task::{Context, Poll};
+/// use abort_safe_future::AsyncDrop;
+/// use abort_safe_future::testing::noop_waker;
+///
+/// // needs two polls to finish
+/// struct Twice {
+///     id: u32,
+///     polled: bool,
+///     log: Rc<RefCell<Vec<u32>>>,
+/// }
+///
+/// impl AsyncDrop for Twice {
+///     fn poll_drop(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+///         if !self.polled {
+///             self.polled = true;
+///             return Poll::Pending;
+///         }
+///         self.log.borrow_mut().push(self.id);
+///         Poll::Ready(())
+///     }
+/// }
+///
+/// let log = Rc::new(RefCell::new(Vec::new()));
+/// let items = (0..3).map(|id| Twice { id, polled: false, log: log.clone() }).collect::<Vec<_>>();
+/// let mut items = ManuallyDrop::new(items);
+/// let waker = noop_waker();
+/// let mut cx = Context::from_waker(&waker);
+///
+/// let mut pendings = 0;
+/// while Pin::new(&mut items).poll_drop(&mut cx).is_pending() {
+///     pendings += 1;
+/// }
+/// assert_eq!(pendings, 3);
+/// assert_eq!(*log.borrow(), [2, 1, 0]);
+/// ```
+impl<T: AsyncDrop> AsyncDrop for Vec<T> {
+    fn poll_drop(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        // elements are never moved, the vec never reallocates here
+        let this = unsafe { pin_manually_drop_as_mut(&mut self).get_unchecked_mut() };
+        while let Some(last) = this.last_mut() {
+            ready!(unsafe { poll_drop_field(last, cx) });
+            // last element has been dropped asynchronously, forget it without running its destructor
+            unsafe { this.set_len(this.len() - 1) };
+        }
+
+        // release the buffer
+        *this = Vec::new();
+        Poll::Ready(())
+    }
+}
+
+/// 按照逆序回收元组中的每个元素
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::cell::RefCell;
+/// use std::mem::ManuallyDrop;
+/// use std::pin::Pin;
+/// use std::rc::Rc;
+/// use std::task::{Context, Poll};
+/// use abort_safe_future::AsyncDrop;
+/// use abort_safe_future::testing::noop_waker;
+///
+/// struct Named(&'static str, Rc<RefCell<Vec<&'static str>>>);
+///
+/// impl AsyncDrop for Named {
+///     fn poll_drop(self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+///         self.1.borrow_mut().push(self.0);
+///         Poll::Ready(())
+///     }
+/// }
+///
+/// let log = Rc::new(RefCell::new(Vec::new()));
+/// let mut guards = ManuallyDrop::new((Named("a", log.clone()), Named("b", log.clone()), Named("c", log.clone())));
+/// let waker = noop_waker();
+/// assert!(Pin::new(&mut guards).poll_drop(&mut Context::from_waker(&waker)).is_ready());
+/// assert_eq!(*log.borrow(), ["c", "b", "a"]);
+/// ```
+macro_rules! tuple_async_drop {
+    (($($name:ident),+), ($($index:tt),+)) => {
+        impl<$($name: AsyncDrop),+> AsyncDrop for ($($name,)+) {
+            fn poll_drop(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+                // elements are never moved out of the tuple
+                let this = unsafe { pin_manually_drop_as_mut(&mut self).get_unchecked_mut() };
+                $(
+                    if unsafe { poll_drop_field(&mut this.$index, cx) }.is_pending() {
+                        return Poll::Pending;
+                    }
+                )+
+                Poll::Ready(())
+            }
+        }
+    };
+}
+
+tuple_async_drop!((A), (0));
+tuple_async_drop!((A, B), (1, 0));
+tuple_async_drop!((A, B, C), (2, 1, 0));
+tuple_async_drop!((A, B, C, D), (3, 2, 1, 0));
+tuple_async_drop!((A, B, C, D, E), (4, 3, 2, 1, 0));
+tuple_async_drop!((A, B, C, D, E, F), (5, 4, 3, 2, 1, 0));
+tuple_async_drop!((A, B, C, D, E, F, G), (6, 5, 4, 3, 2, 1, 0));
+tuple_async_drop!((A, B, C, D, E, F, G, H), (7, 6, 5, 4, 3, 2, 1, 0));
+tuple_async_drop!((A, B, C, D, E, F, G, H, I), (8, 7, 6, 5, 4, 3, 2, 1, 0));
+tuple_async_drop!((A, B, C, D, E, F, G, H, I, J), (9, 8, 7, 6, 5, 4, 3, 2, 1, 0));
+tuple_async_drop!((A, B, C, D, E, F, G, H, I, J, K), (10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0));
+tuple_async_drop!((A, B, C, D, E, F, G, H, I, J, K, L), (11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0));
@@ -1,12 +1,57 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
 use std::future::Future;
 
-use std::mem::ManuallyDrop;
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop};
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::pin::Pin;
-use std::task::{Context, Poll, ready};
-use pin_project::pin_project;
-use crate::future::AbortSafeFuture;
-use crate::helpers::pin_manually_drop_as_mut;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::task::{Context, Poll, Wake, Waker, ready};
+use pin_project::{pin_project, pinned_drop};
+use crate::executor::block_on_cancel;
+use crate::future::{AbortSafeFuture, AbortSafeFutureExt, AbortSafeMarker, BoxAbortSafeFuture, IntoAbortSafeFuture};
+use crate::helpers::{iter_pin_mut, pin_manually_drop_as_mut};
 
+/// 把std的`Future`包装成abort safe future。内部的future完成时会立即析构，取消时直接析构。
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::cell::Cell;
+/// use std::future::Future;
+/// use std::mem::ManuallyDrop;
+/// use std::pin::Pin;
+/// use std::rc::Rc;
+/// use std::task::{Context, Poll};
+/// use abort_safe_future::combinator::Compat;
+/// use abort_safe_future::testing::poll_once;
+///
+/// struct Guard(Rc<Cell<bool>>);
+///
+/// impl Future for Guard {
+///     type Output = ();
+///
+///     fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+///         Poll::Ready(())
+///     }
+/// }
+///
+/// impl Drop for Guard {
+///     fn drop(&mut self) {
+///         self.0.set(true);
+///     }
+/// }
+///
+/// let dropped = Rc::new(Cell::new(false));
+/// let mut fut = ManuallyDrop::new(Compat::new(Guard(dropped.clone())));
+/// assert_eq!(poll_once(Pin::new(&mut fut)), Poll::Ready(()));
+/// assert!(dropped.get());
+/// ```
 #[pin_project]
 pub struct Compat<Fut> {
     #[pin]
@@ -19,14 +64,217 @@ impl<Fut> Compat<Fut> {
             inner: Some(inner),
         }
     }
+
+    /// 第一次`poll`时才调用`f`构造future，省去`Compat::new(async { ... })`。
+    ///
+    /// 在第一次`poll`之前取消的话只析构`f`，之后取消则析构正在执行的future。
+    ///
+    /// ```
+    /// #![feature(arbitrary_self_types)]
+    /// use std::cell::Cell;
+    /// use std::mem::ManuallyDrop;
+    /// use std::pin::Pin;
+    /// use std::task::Poll;
+    /// use abort_safe_future::combinator::Compat;
+    /// use abort_safe_future::testing::poll_once;
+    ///
+    /// let called = Cell::new(false);
+    /// let mut fut = ManuallyDrop::new(Compat::from_fn(|| {
+    ///     called.set(true);
+    ///     std::future::ready(1)
+    /// }));
+    /// assert!(!called.get());
+    /// assert_eq!(poll_once(Pin::new(&mut fut)), Poll::Ready(1));
+    /// assert!(called.get());
+    /// ```
+    pub fn from_fn<F>(f: F) -> CompatFn<F, Fut>
+    where
+        F: FnOnce() -> Fut,
+    {
+        CompatFn {
+            f: Some(f),
+            inner: None,
+        }
+    }
+}
+
+/// `Compat::from_fn`返回的future
+#[pin_project]
+pub struct CompatFn<F, Fut> {
+    f: Option<F>,
+    #[pin]
+    inner: Option<Fut>,
+}
+
+impl<F, Fut> AbortSafeFuture for CompatFn<F, Fut>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future,
+{
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(f) = this.f.take() {
+            this.inner.set(Some(f()));
+        }
+
+        let output = match this.inner.as_mut().as_pin_mut() {
+            Some(fut) => ready!(fut.poll(cx)),
+            None => panic!("CompatFn::poll called after completion or after cancel"),
+        };
+
+        // drop inner future
+        this.inner.set(None);
+        Poll::Ready(output)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        // drop the closure if it has never been polled, otherwise the inner future
+        *this.f = None;
+        this.inner.set(None);
+        Poll::Ready(())
+    }
+}
+
+/// 永远不会完成的future
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use std::pin::Pin;
+/// use std::task::Poll;
+/// use abort_safe_future::testing::{poll_cancel_once, poll_once};
+///
+/// let mut fut = ManuallyDrop::new(abort_safe_future::pending::<u32>());
+/// assert_eq!(poll_once(Pin::new(&mut fut)), Poll::Pending);
+/// assert_eq!(poll_once(Pin::new(&mut fut)), Poll::Pending);
+/// assert_eq!(poll_cancel_once(Pin::new(&mut fut)), Poll::Ready(()));
+/// ```
+pub fn pending<T>() -> Pending<T> {
+    Pending {
+        _marker: PhantomData,
+    }
+}
+
+/// 立即完成的future
+///
+/// ```
+/// assert_eq!(abort_safe_future::executor::block_on(abort_safe_future::ready(5)), 5);
+/// ```
+pub fn ready<T>(t: T) -> Ready<T> {
+    Ready(Some(t))
+}
+
+pub struct Pending<T> {
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Pending<T> {
+    fn clone(&self) -> Self {
+        pending()
+    }
+}
+
+impl<T> AbortSafeFuture for Pending<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Poll::Pending
+    }
+
+    fn poll_cancel(self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+        Poll::Ready(())
+    }
 }
 
-pub fn pending<T>() -> Compat<futures::future::Pending<T>> {
-    Compat::new(futures::future::pending())
+/// 永远不会完成的future，与`pending()`相同，但是`Copy`，并且有自己的类型，
+/// 可以在`select`中作为指定了`Output`的占位分支
+///
+/// ```
+/// use abort_safe_future::{ready, select, Either};
+/// use abort_safe_future::combinator::Never;
+/// use abort_safe_future::executor::block_on;
+///
+/// let never = Never::<u32>::new();
+/// let copy = never;
+/// assert_eq!(block_on(select(never, ready("done"))), Either::Right("done"));
+/// assert_eq!(block_on(select(copy, ready("again"))), Either::Right("again"));
+/// ```
+pub struct Never<T> {
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Never<T> {
+    pub fn new() -> Self {
+        Self {
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Default for Never<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Clone for Never<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Never<T> {}
+
+impl<T> AbortSafeFuture for Never<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Poll::Pending
+    }
+
+    fn poll_cancel(self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+        Poll::Ready(())
+    }
 }
 
-pub fn ready<T>(t: T) -> Compat<futures::future::Ready<T>> {
-    Compat::new(futures::future::ready(t))
+/// `ready`返回的future。
+///
+/// `T: Clone`时可以克隆，克隆出的future是独立的。已经完成或者被取消的`Ready`不再持有值，
+/// 克隆它得到的也是一个已经完成的`Ready`。
+///
+/// ```
+/// use abort_safe_future::executor::block_on;
+///
+/// let fut = abort_safe_future::ready(7);
+/// let other = fut.clone();
+/// assert_eq!(block_on(fut), 7);
+/// assert_eq!(block_on(other), 7);
+/// ```
+#[derive(Clone)]
+pub struct Ready<T>(Option<T>);
+
+impl<T> Unpin for Ready<T> {}
+
+impl<T> AbortSafeFuture for Ready<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = pin_manually_drop_as_mut(&mut self).get_mut();
+        match this.0.take() {
+            Some(t) => Poll::Ready(t),
+            None => panic!("Ready::poll called after completion or after cancel"),
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).get_mut();
+        // drop the value if it has not been taken
+        this.0 = None;
+        Poll::Ready(())
+    }
 }
 
 
@@ -56,11 +304,65 @@ impl<Fut: Future> AbortSafeFuture for Compat<Fut> {
     }
 }
 
+/// 先执行`Fut1`，再用它的结果调用`F`得到`Fut2`并执行。
+///
+/// 回收顺序：`Fut1`在完成时回收，之后才调用`F`；取消时只有正在执行的那一个会被取消，然后析构`F`。
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use abort_safe_future::{pending, ready, AbortSafeFutureExt};
+/// use abort_safe_future::testing::{poll_cancel_once, poll_once, DropOrderRecorder};
+///
+/// let recorder = DropOrderRecorder::new();
+/// let fut = recorder.wrap("first", ready(1)).then(|_| recorder.wrap("second", pending::<u32>()));
+/// let mut fut = Box::pin(ManuallyDrop::new(fut));
+/// assert!(poll_once(fut.as_mut()).is_pending());
+/// assert_eq!(recorder.events(), ["first"]);
+/// assert!(poll_cancel_once(fut.as_mut()).is_ready());
+/// assert_eq!(recorder.events(), ["first", "second"]);
+///
+/// // a cancelled `Then` stays pending instead of panicking
+/// assert!(poll_once(fut.as_mut()).is_pending());
+/// ```
+///
+/// 一次`poll`内会连续推进所有已经就绪的阶段，只有子future真的返回`Poll::Pending`时才会返回：
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use std::sync::atomic::Ordering;
+/// use std::task::Poll;
+/// use abort_safe_future::{poll_fn, ready, AbortSafeFutureExt};
+/// use abort_safe_future::testing::{counting_waker, poll_once_with};
+///
+/// let fut = ready(1)
+///     .then(|x| ready(x + 1))
+///     .then(|x| {
+///         let mut stalled = false;
+///         poll_fn(move |cx| {
+///             if stalled {
+///                 return Poll::Ready(x + 1);
+///             }
+///             stalled = true;
+///             cx.waker().wake_by_ref();
+///             Poll::Pending
+///         })
+///     })
+///     .then(|x| ready(x + 1));
+/// let mut fut = Box::pin(ManuallyDrop::new(fut));
+/// let (waker, wakes) = counting_waker();
+/// assert!(poll_once_with(fut.as_mut(), &waker).is_pending());
+/// assert_eq!(wakes.load(Ordering::SeqCst), 1);
+/// assert_eq!(poll_once_with(fut.as_mut(), &waker), Poll::Ready(4));
+/// assert_eq!(wakes.load(Ordering::SeqCst), 1);
+/// ```
 #[pin_project]
-pub struct Then<Fut1, Fut2, F> {
+pub struct Then<Fut1, Fut2, F, M = AbortSafeMarker> {
     #[pin]
     inner: ThenInner<Fut1, Fut2>,
     f: Option<F>,
+    _marker: PhantomData<fn() -> M>,
 }
 
 
@@ -72,41 +374,45 @@ enum ThenInner<Fut1, Fut2> {
     Canceled,
 }
 
-impl<Fut1, Fut2, F> Then<Fut1, Fut2, F> {
+impl<Fut1, Fut2, F, M> Then<Fut1, Fut2, F, M> {
     pub fn new(fut1: Fut1, f: F) -> Self {
         Self {
             inner: ThenInner::Fut1(ManuallyDrop::new(fut1)),
             f: Some(f),
+            _marker: PhantomData,
         }
     }
 }
 
-impl<Fut1, Fut2, F> AbortSafeFuture for Then<Fut1, Fut2, F>
+impl<Fut1, Fut2, F, B, M> AbortSafeFuture for Then<Fut1, Fut2, F, M>
 where
     Fut1: AbortSafeFuture,
     Fut2: AbortSafeFuture,
-    F: FnOnce(Fut1::Output) -> Fut2,
+    F: FnOnce(Fut1::Output) -> B,
+    B: IntoAbortSafeFuture<M, Future = Fut2>,
 {
     type Output = Fut2::Output;
 
     fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let mut this = pin_manually_drop_as_mut(&mut self).project();
-        let inner = this.inner.as_mut().project();
-        match inner {
-            ThenProj::Fut1(fut1) => {
-                let output = ready!(fut1.poll(cx));
-                let f = this.f.take().unwrap();
-                this.inner.set(ThenInner::Fut2(ManuallyDrop::new(f(output))));
-                cx.waker().wake_by_ref();
-                Poll::Pending
-            }
-            ThenProj::Fut2(fut2) => {
-                let output = ready!(fut2.poll(cx));
-                this.inner.set(ThenInner::Done);
-                Poll::Ready(output)
+        // advance to `Fut2` within the same call, only return `Pending` when an inner future does
+        loop {
+            let inner = this.inner.as_mut().project();
+            match inner {
+                ThenProj::Fut1(fut1) => {
+                    let output = ready!(fut1.poll(cx));
+                    let f = this.f.take().unwrap();
+                    this.inner.set(ThenInner::Fut2(ManuallyDrop::new(f(output).into_abort_safe())));
+                }
+                ThenProj::Fut2(fut2) => {
+                    let output = ready!(fut2.poll(cx));
+                    this.inner.set(ThenInner::Done);
+                    return Poll::Ready(output);
+                }
+                ThenProj::Done => panic!("Then::poll called after completion"),
+                // like `Fuse`, a canceled `Then` never completes
+                ThenProj::Canceled => return Poll::Pending,
             }
-            ThenProj::Done => panic!("AndThen::poll called after completion"),
-            ThenProj::Canceled => panic!("AndThen::poll called after cancel"),
         }
     }
 
@@ -114,20 +420,2936 @@ where
         let mut this = pin_manually_drop_as_mut(&mut self).project();
         let inner = this.inner.as_mut().project();
         match inner {
-            ThenProj::Fut1(fut1) => {
-                fut1.poll_cancel(cx)
-            }
-            ThenProj::Fut2(fut2) => {
-                fut2.poll_cancel(cx)
-            }
-            ThenProj::Done => {
-                this.inner.set(ThenInner::Canceled);
-                Poll::Ready(())
-            }
-            ThenProj::Canceled => {
+            ThenProj::Fut1(fut1) => ready!(fut1.poll_cancel(cx)),
+            ThenProj::Fut2(fut2) => ready!(fut2.poll_cancel(cx)),
+            ThenProj::Done | ThenProj::Canceled => {}
+        }
+
+        // drop closure
+        *this.f = None;
+        this.inner.set(ThenInner::Canceled);
+        Poll::Ready(())
+    }
+}
+
+/// 用`F`转换`Fut`的结果。
+///
+/// 回收顺序：先回收`Fut`，再调用`F`；取消时先取消`Fut`，再析构`F`。
+///
+/// ```
+/// use abort_safe_future::{ready, AbortSafeFutureExt};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::testing::DropOrderRecorder;
+///
+/// let recorder = DropOrderRecorder::new();
+/// let fut = recorder.wrap("inner", ready(1)).map(|x| {
+///     recorder.record("map");
+///     x + 1
+/// });
+/// assert_eq!(block_on(fut), 2);
+/// assert_eq!(recorder.events(), ["inner", "map"]);
+/// ```
+///
+/// `F`只会被调用一次，而且调用时`Fut`已经被回收：
+///
+/// ```
+/// use std::cell::Cell;
+/// use std::task::Poll;
+/// use abort_safe_future::{poll_fn, AbortSafeFutureExt};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::testing::checked;
+///
+/// let mut polls = 0;
+/// let (inner, finalized) = checked(poll_fn(move |cx| {
+///     polls += 1;
+///     if polls < 2 {
+///         cx.waker().wake_by_ref();
+///         return Poll::Pending;
+///     }
+///     Poll::Ready(polls)
+/// }));
+/// let calls = Cell::new(0);
+/// let fut = inner.map(|x| {
+///     calls.set(calls.get() + 1);
+///     assert!(finalized.is_finalized());
+///     x * 10
+/// });
+/// assert_eq!(block_on(fut), 20);
+/// assert_eq!(calls.get(), 1);
+/// ```
+#[pin_project]
+pub struct Map<Fut, F> {
+    #[pin]
+    inner: ManuallyDrop<Fut>,
+    f: Option<F>,
+}
+
+impl<Fut, F> Map<Fut, F> {
+    pub fn new(fut: Fut, f: F) -> Self {
+        Self {
+            inner: ManuallyDrop::new(fut),
+            f: Some(f),
+        }
+    }
+}
+
+impl<Fut, F, T> AbortSafeFuture for Map<Fut, F>
+where
+    Fut: AbortSafeFuture,
+    F: FnOnce(Fut::Output) -> T,
+{
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        if this.f.is_none() {
+            panic!("Map::poll called after completion or after cancel")
+        }
+
+        let output = ready!(this.inner.poll(cx));
+        let f = this.f.take().unwrap();
+        Poll::Ready(f(output))
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        if this.f.is_some() {
+            ready!(this.inner.poll_cancel(cx));
+            // drop closure
+            *this.f = None;
+        }
+
+        Poll::Ready(())
+    }
+}
+
+/// 完成时用`F`观察`Fut`的结果，结果原样返回。
+///
+/// 取消时`F`不会被调用：
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::cell::Cell;
+/// use std::mem::ManuallyDrop;
+/// use abort_safe_future::{pending, AbortSafeFutureExt};
+/// use abort_safe_future::testing::{checked, poll_cancel_once, poll_once};
+///
+/// let called = Cell::new(false);
+/// let (inner, finalized) = checked(pending::<u32>());
+/// let mut fut = Box::pin(ManuallyDrop::new(inner.inspect(|_| called.set(true))));
+/// assert!(poll_once(fut.as_mut()).is_pending());
+/// assert!(poll_cancel_once(fut.as_mut()).is_ready());
+/// assert!(finalized.is_finalized());
+/// assert!(!called.get());
+/// ```
+#[pin_project]
+pub struct Inspect<Fut, F> {
+    #[pin]
+    inner: ManuallyDrop<Fut>,
+    f: Option<F>,
+}
+
+impl<Fut, F> Inspect<Fut, F> {
+    pub fn new(fut: Fut, f: F) -> Self {
+        Self {
+            inner: ManuallyDrop::new(fut),
+            f: Some(f),
+        }
+    }
+}
+
+impl<Fut, F> AbortSafeFuture for Inspect<Fut, F>
+where
+    Fut: AbortSafeFuture,
+    F: FnOnce(&Fut::Output),
+{
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        if this.f.is_none() {
+            panic!("Inspect::poll called after completion or after cancel")
+        }
+
+        let output = ready!(this.inner.poll(cx));
+        let f = this.f.take().unwrap();
+        f(&output);
+        Poll::Ready(output)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        if this.f.is_some() {
+            ready!(this.inner.poll_cancel(cx));
+            // drop closure
+            *this.f = None;
+        }
+
+        Poll::Ready(())
+    }
+}
+
+/// 在`poll_cancel`第一次被调用时执行`f`，用于观察future是否被取消。
+/// 正常完成时`f`不会被执行
+///
+/// ```
+/// use std::cell::Cell;
+/// use abort_safe_future::{pending, ready, select, AbortSafeFutureExt, Either};
+/// use abort_safe_future::executor::block_on;
+///
+/// let winner_canceled = Cell::new(false);
+/// let loser_canceled = Cell::new(false);
+/// let fut = select(
+///     ready(1).inspect_cancel(|| winner_canceled.set(true)),
+///     pending::<u32>().inspect_cancel(|| loser_canceled.set(true)),
+/// );
+/// assert_eq!(block_on(fut), Either::Left(1));
+/// assert!(!winner_canceled.get());
+/// assert!(loser_canceled.get());
+/// ```
+#[pin_project]
+pub struct InspectCancel<Fut, F> {
+    #[pin]
+    inner: ManuallyDrop<Fut>,
+    f: Option<F>,
+    done: bool,
+}
+
+impl<Fut, F> InspectCancel<Fut, F> {
+    pub fn new(fut: Fut, f: F) -> Self {
+        Self {
+            inner: ManuallyDrop::new(fut),
+            f: Some(f),
+            done: false,
+        }
+    }
+}
+
+impl<Fut, F> AbortSafeFuture for InspectCancel<Fut, F>
+where
+    Fut: AbortSafeFuture,
+    F: FnOnce(),
+{
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        if *this.done {
+            panic!("InspectCancel::poll called after completion or after cancel")
+        }
+
+        let output = ready!(this.inner.poll(cx));
+        *this.done = true;
+        // drop closure
+        *this.f = None;
+        Poll::Ready(output)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        if *this.done {
+            return Poll::Ready(());
+        }
+
+        if let Some(f) = this.f.take() {
+            f();
+        }
+
+        ready!(this.inner.poll_cancel(cx));
+        *this.done = true;
+        Poll::Ready(())
+    }
+}
+
+/// 执行`Fut`，无论它完成还是被取消，之后都会把`C`执行到完成，类似于`finally`。
+///
+/// 取消时先取消`Fut`，再`poll` `C`直到完成。`C`正在执行时被取消，也会继续`poll`它而不是取消它。
+/// 已经保存的结果会被析构。
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::cell::Cell;
+/// use std::mem::ManuallyDrop;
+/// use std::task::Poll;
+/// use abort_safe_future::{pending, poll_fn, AbortSafeFutureExt};
+/// use abort_safe_future::testing::{poll_cancel_once, poll_once};
+///
+/// let ran = Cell::new(0);
+/// let mut yielded = false;
+/// let cleanup = poll_fn(|cx| {
+///     if !yielded {
+///         yielded = true;
+///         cx.waker().wake_by_ref();
+///         return Poll::Pending;
+///     }
+///     ran.set(ran.get() + 1);
+///     Poll::Ready(())
+/// });
+///
+/// let mut fut = Box::pin(ManuallyDrop::new(pending::<u32>().guard(cleanup)));
+/// assert!(poll_once(fut.as_mut()).is_pending());
+/// // the cleanup is still running, canceling again keeps driving it
+/// assert!(poll_cancel_once(fut.as_mut()).is_pending());
+/// assert!(poll_cancel_once(fut.as_mut()).is_ready());
+/// assert!(poll_cancel_once(fut.as_mut()).is_ready());
+/// assert_eq!(ran.get(), 1);
+/// ```
+#[pin_project]
+pub struct Guard<Fut: AbortSafeFuture, C> {
+    #[pin]
+    fut: Option<ManuallyDrop<Fut>>,
+    #[pin]
+    cleanup: Option<ManuallyDrop<C>>,
+    output: Option<Fut::Output>,
+}
+
+impl<Fut: AbortSafeFuture, C> Guard<Fut, C> {
+    pub fn new(fut: Fut, cleanup: C) -> Self {
+        Self {
+            fut: Some(ManuallyDrop::new(fut)),
+            cleanup: Some(ManuallyDrop::new(cleanup)),
+            output: None,
+        }
+    }
+}
+
+impl<Fut, C> AbortSafeFuture for Guard<Fut, C>
+where
+    Fut: AbortSafeFuture,
+    C: AbortSafeFuture<Output = ()>,
+{
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+            let output = ready!(fut.poll(cx));
+            this.fut.set(None);
+            *this.output = Some(output);
+        }
+
+        if let Some(cleanup) = this.cleanup.as_mut().as_pin_mut() {
+            ready!(cleanup.poll(cx));
+            this.cleanup.set(None);
+        }
+
+        match this.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => panic!("Guard::poll called after completion or after cancel"),
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+            ready!(fut.poll_cancel(cx));
+            this.fut.set(None);
+        }
+
+        // the cleanup runs to completion instead of being canceled
+        if let Some(cleanup) = this.cleanup.as_mut().as_pin_mut() {
+            ready!(cleanup.poll(cx));
+            this.cleanup.set(None);
+        }
+
+        // drop the saved output
+        *this.output = None;
+        Poll::Ready(())
+    }
+}
+
+/// 执行future直到完成，并保存它的结果，用于实现`join`一类的组合子。
+///
+/// 取消时，还在执行的future会被取消，已经保存的结果会被析构。
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use std::rc::Rc;
+/// use std::task::Poll;
+/// use abort_safe_future::{pending, ready, PinManuallyDropExt};
+/// use abort_safe_future::combinator::MaybeDone;
+/// use abort_safe_future::testing::{checked, poll_cancel_once, poll_once};
+///
+/// // Future -> Done -> Gone
+/// let mut done = Box::pin(ManuallyDrop::new(MaybeDone::new(ready(1))));
+/// assert!(!done.is_done());
+/// assert_eq!(poll_once(done.as_mut()), Poll::Ready(()));
+/// assert!(done.is_done());
+/// assert_eq!(done.project_manually_drop().take_output(), Some(1));
+/// assert!(matches!(**done, MaybeDone::Gone));
+/// assert_eq!(done.project_manually_drop().take_output(), None);
+/// assert!(poll_cancel_once(done.as_mut()).is_ready());
+///
+/// // cancelled in `Future`: the inner future is cancelled
+/// let (inner, finalized) = checked(pending::<u32>());
+/// let mut running = Box::pin(ManuallyDrop::new(MaybeDone::new(inner)));
+/// assert!(poll_once(running.as_mut()).is_pending());
+/// assert!(poll_cancel_once(running.as_mut()).is_ready());
+/// assert!(finalized.is_finalized());
+/// assert!(matches!(**running, MaybeDone::Gone));
+///
+/// // cancelled in `Done`: the output is dropped
+/// let output = Rc::new(());
+/// let mut stored = Box::pin(ManuallyDrop::new(MaybeDone::new(ready(output.clone()))));
+/// assert!(poll_once(stored.as_mut()).is_ready());
+/// assert!(poll_cancel_once(stored.as_mut()).is_ready());
+/// assert_eq!(Rc::strong_count(&output), 1);
+/// ```
+#[pin_project(project = MaybeDoneProj)]
+pub enum MaybeDone<Fut: AbortSafeFuture> {
+    Future(#[pin] ManuallyDrop<Fut>),
+    Done(Fut::Output),
+    Gone,
+}
+
+impl<Fut: AbortSafeFuture> MaybeDone<Fut> {
+    pub fn new(fut: Fut) -> Self {
+        MaybeDone::Future(ManuallyDrop::new(fut))
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self, MaybeDone::Done(_))
+    }
+
+    /// 取走保存的结果，之后变成`Gone`状态。还没有完成时返回`None`
+    pub fn take_output(self: Pin<&mut Self>) -> Option<Fut::Output> {
+        if !self.is_done() {
+            return None;
+        }
+
+        // the output is not structurally pinned
+        match mem::replace(unsafe { self.get_unchecked_mut() }, MaybeDone::Gone) {
+            MaybeDone::Done(output) => Some(output),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<Fut: AbortSafeFuture> AbortSafeFuture for MaybeDone<Fut> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self);
+        match this.as_mut().project() {
+            MaybeDoneProj::Future(fut) => {
+                let output = ready!(fut.poll(cx));
+                this.set(MaybeDone::Done(output));
                 Poll::Ready(())
             }
+            MaybeDoneProj::Done(_) => Poll::Ready(()),
+            MaybeDoneProj::Gone => panic!("MaybeDone::poll called after the output was taken or after cancel"),
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self);
+        match this.as_mut().project() {
+            MaybeDoneProj::Future(fut) => ready!(fut.poll_cancel(cx)),
+            // drop output
+            MaybeDoneProj::Done(_) => {}
+            MaybeDoneProj::Gone => return Poll::Ready(()),
+        }
+
+        this.set(MaybeDone::Gone);
+        Poll::Ready(())
+    }
+}
+
+/// 同时执行两个future，返回两边的结果。
+///
+/// 每次`poll`都会`poll`两边还没有完成的future，总是先`poll`左边再`poll`右边。
+///
+/// 回收顺序：每一边在自己完成时回收；取消时先取消左边，再取消右边，
+/// 一边的`poll_cancel`返回`Poll::Pending`不会推迟另一边。
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use abort_safe_future::{join, pending};
+/// use abort_safe_future::testing::{poll_cancel_once, poll_once, DropOrderRecorder};
+///
+/// let recorder = DropOrderRecorder::new();
+/// let fut = join(recorder.wrap("left", pending::<u32>()), recorder.wrap("right", pending::<u32>()));
+/// let mut fut = Box::pin(ManuallyDrop::new(fut));
+/// assert!(poll_once(fut.as_mut()).is_pending());
+/// assert!(poll_cancel_once(fut.as_mut()).is_ready());
+/// assert_eq!(recorder.events(), ["left", "right"]);
+/// ```
+///
+/// 一边已经完成时取消，另一边会被取消，已经完成的一边的结果会被析构：
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use std::rc::Rc;
+/// use abort_safe_future::{join, pending, ready};
+/// use abort_safe_future::testing::{checked, poll_cancel_once, poll_once};
+///
+/// let output = Rc::new(());
+/// let (left, left_finalized) = checked(ready(output.clone()));
+/// let (right, right_finalized) = checked(pending::<()>());
+/// let mut fut = Box::pin(ManuallyDrop::new(join(left, right)));
+/// assert!(poll_once(fut.as_mut()).is_pending());
+/// assert!(left_finalized.is_finalized());
+/// assert!(!right_finalized.is_finalized());
+///
+/// assert!(poll_cancel_once(fut.as_mut()).is_ready());
+/// assert!(right_finalized.is_finalized());
+/// assert_eq!(Rc::strong_count(&output), 1);
+/// ```
+pub fn join<Fut1, Fut2>(fut1: Fut1, fut2: Fut2) -> Join<Fut1, Fut2>
+where
+    Fut1: AbortSafeFuture,
+    Fut2: AbortSafeFuture,
+{
+    Join::new(fut1, fut2)
+}
+
+/// `join`返回的future，每次`poll`都先`poll`左边再`poll`右边，取消时也是先左后右
+#[pin_project]
+pub struct Join<Fut1: AbortSafeFuture, Fut2: AbortSafeFuture> {
+    #[pin]
+    fut1: ManuallyDrop<MaybeDone<Fut1>>,
+    #[pin]
+    fut2: ManuallyDrop<MaybeDone<Fut2>>,
+}
+
+impl<Fut1: AbortSafeFuture, Fut2: AbortSafeFuture> Join<Fut1, Fut2> {
+    pub fn new(fut1: Fut1, fut2: Fut2) -> Self {
+        Self {
+            fut1: ManuallyDrop::new(MaybeDone::new(fut1)),
+            fut2: ManuallyDrop::new(MaybeDone::new(fut2)),
+        }
+    }
+}
+
+impl<Fut1, Fut2> AbortSafeFuture for Join<Fut1, Fut2>
+where
+    Fut1: AbortSafeFuture,
+    Fut2: AbortSafeFuture,
+{
+    type Output = (Fut1::Output, Fut2::Output);
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if matches!(**this.fut1, MaybeDone::Gone) || matches!(**this.fut2, MaybeDone::Gone) {
+            panic!("Join::poll called after completion or after cancel")
+        }
+
+        // poll both sides, never skip one because the other is pending
+        let ready1 = this.fut1.as_mut().poll(cx).is_ready();
+        let ready2 = this.fut2.as_mut().poll(cx).is_ready();
+        if !(ready1 && ready2) {
+            return Poll::Pending;
         }
 
+        let output1 = pin_manually_drop_as_mut(&mut this.fut1).take_output().unwrap();
+        let output2 = pin_manually_drop_as_mut(&mut this.fut2).take_output().unwrap();
+        Poll::Ready((output1, output2))
     }
-}
\ No newline at end of file
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        // cancel both sides, never skip one because the other is pending
+        let canceled1 = this.fut1.as_mut().poll_cancel(cx).is_ready();
+        let canceled2 = this.fut2.as_mut().poll_cancel(cx).is_ready();
+        if canceled1 && canceled2 {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// 就是`join`，`Join`本来就先`poll`左边。用这个名字可以在调用处表明依赖这个顺序
+///
+/// ```
+/// use std::cell::RefCell;
+/// use std::task::Poll;
+/// use abort_safe_future::{join_biased, poll_fn};
+/// use abort_safe_future::executor::block_on;
+///
+/// let order = RefCell::new(Vec::new());
+/// let branch = |name: &'static str, mut rounds: u32| {
+///     let order = &order;
+///     poll_fn(move |cx| {
+///         order.borrow_mut().push(name);
+///         if rounds == 0 {
+///             return Poll::Ready(name);
+///         }
+///         rounds -= 1;
+///         cx.waker().wake_by_ref();
+///         Poll::Pending
+///     })
+/// };
+///
+/// assert_eq!(block_on(join_biased(branch("a", 2), branch("b", 2))), ("a", "b"));
+/// assert_eq!(*order.borrow(), ["a", "b", "a", "b", "a", "b"]);
+/// ```
+pub fn join_biased<Fut1, Fut2>(fut1: Fut1, fut2: Fut2) -> Join<Fut1, Fut2>
+where
+    Fut1: AbortSafeFuture,
+    Fut2: AbortSafeFuture,
+{
+    join(fut1, fut2)
+}
+
+/// 生成固定数量的`join`，和`Join`一样，每次`poll`都会`poll`所有还没有完成的future
+macro_rules! join_n {
+    ($(#[$doc:meta])* $fn_name:ident, $name:ident, $(($Fut:ident, $fut:ident)),+) => {
+        $(#[$doc])*
+        pub fn $fn_name<$($Fut),+>($($fut: $Fut),+) -> $name<$($Fut),+>
+        where
+            $($Fut: AbortSafeFuture),+
+        {
+            $name::new($($fut),+)
+        }
+
+        #[pin_project]
+        pub struct $name<$($Fut: AbortSafeFuture),+> {
+            $(
+                #[pin]
+                $fut: ManuallyDrop<MaybeDone<$Fut>>,
+            )+
+        }
+
+        impl<$($Fut: AbortSafeFuture),+> $name<$($Fut),+> {
+            pub fn new($($fut: $Fut),+) -> Self {
+                Self {
+                    $($fut: ManuallyDrop::new(MaybeDone::new($fut)),)+
+                }
+            }
+        }
+
+        impl<$($Fut: AbortSafeFuture),+> AbortSafeFuture for $name<$($Fut),+> {
+            type Output = ($($Fut::Output,)+);
+
+            fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+                let mut this = pin_manually_drop_as_mut(&mut self).project();
+                let mut gone = false;
+                $(gone |= matches!(**this.$fut, MaybeDone::Gone);)+
+                if gone {
+                    panic!(concat!(stringify!($name), "::poll called after completion or after cancel"))
+                }
+
+                // poll every branch, never skip one because another is pending
+                let mut ready = true;
+                $(ready &= this.$fut.as_mut().poll(cx).is_ready();)+
+                if !ready {
+                    return Poll::Pending;
+                }
+
+                Poll::Ready(($(pin_manually_drop_as_mut(&mut this.$fut).take_output().unwrap(),)+))
+            }
+
+            fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+                let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+                // cancel every branch, never skip one because another is pending
+                let mut canceled = true;
+                $(canceled &= this.$fut.as_mut().poll_cancel(cx).is_ready();)+
+                if canceled {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            }
+        }
+    };
+}
+
+join_n!(
+    /// 同时执行三个future，返回所有的结果
+    ///
+    /// ```
+    /// use abort_safe_future::{join3, ready};
+    /// use abort_safe_future::executor::block_on;
+    /// use abort_safe_future::testing::checked;
+    ///
+    /// let (a, a_finalized) = checked(ready(1u8));
+    /// let (b, b_finalized) = checked(ready(String::from("b")));
+    /// let (c, c_finalized) = checked(ready(true));
+    /// assert_eq!(block_on(join3(a, b, c)), (1, String::from("b"), true));
+    /// assert!(a_finalized.is_finalized() && b_finalized.is_finalized() && c_finalized.is_finalized());
+    /// ```
+    join3, Join3, (Fut1, fut1), (Fut2, fut2), (Fut3, fut3)
+);
+join_n!(
+    /// 同时执行四个future，返回所有的结果
+    join4, Join4, (Fut1, fut1), (Fut2, fut2), (Fut3, fut3), (Fut4, fut4)
+);
+join_n!(
+    /// 同时执行五个future，返回所有的结果
+    join5, Join5, (Fut1, fut1), (Fut2, fut2), (Fut3, fut3), (Fut4, fut4), (Fut5, fut5)
+);
+
+/// 同时执行两个返回`Result`的future。
+///
+/// 任何一边返回`Err`时，先对另一边调用`poll_cancel`直到完成，再返回这个错误。
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use std::task::Poll;
+/// use abort_safe_future::{pending, ready, try_join};
+/// use abort_safe_future::testing::{checked, poll_once};
+///
+/// let (pending_side, finalized) = checked(pending::<Result<u32, &str>>());
+/// let mut fut = Box::pin(ManuallyDrop::new(try_join(ready(Err::<u32, _>("boom")), pending_side)));
+/// assert_eq!(poll_once(fut.as_mut()), Poll::Ready(Err("boom")));
+/// assert!(finalized.is_finalized());
+/// ```
+pub fn try_join<Fut1, Fut2, T1, T2, E>(fut1: Fut1, fut2: Fut2) -> TryJoin<Fut1, Fut2>
+where
+    Fut1: AbortSafeFuture<Output = Result<T1, E>>,
+    Fut2: AbortSafeFuture<Output = Result<T2, E>>,
+{
+    TryJoin::new(fut1, fut2)
+}
+
+#[pin_project]
+pub struct TryJoin<Fut1: AbortSafeFuture, Fut2: AbortSafeFuture> {
+    #[pin]
+    fut1: Option<ManuallyDrop<Fut1>>,
+    #[pin]
+    fut2: Option<ManuallyDrop<Fut2>>,
+    output1: Option<Fut1::Output>,
+    output2: Option<Fut2::Output>,
+}
+
+impl<Fut1: AbortSafeFuture, Fut2: AbortSafeFuture> TryJoin<Fut1, Fut2> {
+    pub fn new(fut1: Fut1, fut2: Fut2) -> Self {
+        Self {
+            fut1: Some(ManuallyDrop::new(fut1)),
+            fut2: Some(ManuallyDrop::new(fut2)),
+            output1: None,
+            output2: None,
+        }
+    }
+}
+
+impl<Fut1, Fut2, T1, T2, E> AbortSafeFuture for TryJoin<Fut1, Fut2>
+where
+    Fut1: AbortSafeFuture<Output = Result<T1, E>>,
+    Fut2: AbortSafeFuture<Output = Result<T2, E>>,
+{
+    type Output = Result<(T1, T2), E>;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        let failed = |output1: &Option<Result<T1, E>>, output2: &Option<Result<T2, E>>| {
+            matches!(output1, Some(Err(_))) || matches!(output2, Some(Err(_)))
+        };
+
+        if !failed(this.output1, this.output2) {
+            if let Some(fut1) = this.fut1.as_mut().as_pin_mut() {
+                if let Poll::Ready(output) = fut1.poll(cx) {
+                    *this.output1 = Some(output);
+                    this.fut1.set(None);
+                }
+            }
+        }
+
+        if !failed(this.output1, this.output2) {
+            if let Some(fut2) = this.fut2.as_mut().as_pin_mut() {
+                if let Poll::Ready(output) = fut2.poll(cx) {
+                    *this.output2 = Some(output);
+                    this.fut2.set(None);
+                }
+            }
+        }
+
+        if failed(this.output1, this.output2) {
+            // one side has failed, the other one must be canceled before reporting the error
+            if let Some(fut1) = this.fut1.as_mut().as_pin_mut() {
+                if fut1.poll_cancel(cx).is_ready() {
+                    this.fut1.set(None);
+                }
+            }
+
+            if let Some(fut2) = this.fut2.as_mut().as_pin_mut() {
+                if fut2.poll_cancel(cx).is_ready() {
+                    this.fut2.set(None);
+                }
+            }
+
+            if this.fut1.is_some() || this.fut2.is_some() {
+                return Poll::Pending;
+            }
+
+            return match (this.output1.take(), this.output2.take()) {
+                (Some(Err(e)), _) | (_, Some(Err(e))) => Poll::Ready(Err(e)),
+                _ => unreachable!(),
+            };
+        }
+
+        if this.fut1.is_some() || this.fut2.is_some() {
+            return Poll::Pending;
+        }
+
+        match (this.output1.take(), this.output2.take()) {
+            (Some(Ok(output1)), Some(Ok(output2))) => Poll::Ready(Ok((output1, output2))),
+            _ => panic!("TryJoin::poll called after completion or after cancel"),
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        if let Some(fut1) = this.fut1.as_mut().as_pin_mut() {
+            if fut1.poll_cancel(cx).is_ready() {
+                this.fut1.set(None);
+            }
+        }
+
+        if let Some(fut2) = this.fut2.as_mut().as_pin_mut() {
+            if fut2.poll_cancel(cx).is_ready() {
+                this.fut2.set(None);
+            }
+        }
+
+        if this.fut1.is_some() || this.fut2.is_some() {
+            return Poll::Pending;
+        }
+
+        // drop outputs
+        *this.output1 = None;
+        *this.output2 = None;
+        Poll::Ready(())
+    }
+}
+
+/// 同时执行所有的future，按照原来的顺序返回结果。
+///
+/// ```
+/// use std::cell::RefCell;
+/// use std::task::Poll;
+/// use abort_safe_future::{join_all, poll_fn};
+/// use abort_safe_future::executor::block_on;
+///
+/// let finished = RefCell::new(Vec::new());
+/// let futs = (0..10).map(|i| {
+///     let finished = &finished;
+///     let mut left = 10 - i;
+///     poll_fn(move |cx| {
+///         left -= 1;
+///         if left > 0 {
+///             cx.waker().wake_by_ref();
+///             return Poll::Pending;
+///         }
+///         finished.borrow_mut().push(i);
+///         Poll::Ready(i)
+///     })
+/// });
+///
+/// assert_eq!(block_on(join_all(futs)), (0..10).collect::<Vec<_>>());
+/// assert_eq!(*finished.borrow(), (0..10).rev().collect::<Vec<_>>());
+/// ```
+pub fn join_all<I>(iter: I) -> JoinAll<I::Item>
+where
+    I: IntoIterator,
+    I::Item: AbortSafeFuture,
+{
+    JoinAll::new(iter.into_iter().map(ManuallyDrop::new).collect())
+}
+
+#[pin_project]
+pub struct JoinAll<Fut: AbortSafeFuture> {
+    futs: Pin<Box<[Option<ManuallyDrop<Fut>>]>>,
+    outputs: Option<Vec<Option<Fut::Output>>>,
+}
+
+impl<Fut: AbortSafeFuture> JoinAll<Fut> {
+    pub fn new(futs: Vec<ManuallyDrop<Fut>>) -> Self {
+        let outputs = futs.iter().map(|_| None).collect();
+        let futs: Box<[_]> = futs.into_iter().map(Some).collect();
+        Self {
+            futs: futs.into(),
+            outputs: Some(outputs),
+        }
+    }
+}
+
+impl<Fut: AbortSafeFuture> AbortSafeFuture for JoinAll<Fut> {
+    type Output = Vec<Fut::Output>;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        let outputs = match this.outputs {
+            Some(outputs) => outputs,
+            None => panic!("JoinAll::poll called after completion or after cancel"),
+        };
+
+        let mut all_done = true;
+        for (mut elem, output) in iter_pin_mut(this.futs.as_mut()).zip(outputs.iter_mut()) {
+            if let Some(fut) = elem.as_mut().as_pin_mut() {
+                if let Poll::Ready(out) = fut.poll(cx) {
+                    *output = Some(out);
+                    elem.set(None);
+                } else {
+                    all_done = false;
+                }
+            }
+        }
+
+        if !all_done {
+            return Poll::Pending;
+        }
+
+        // release the slice, `JoinAll` itself is never dropped
+        *this.futs = Box::pin([]);
+        let outputs = this.outputs.take().unwrap();
+        Poll::Ready(outputs.into_iter().map(Option::unwrap).collect())
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+
+        // walk the whole slice, finished elements are already `None`
+        let mut all_canceled = true;
+        for mut elem in iter_pin_mut(this.futs.as_mut()) {
+            if let Some(fut) = elem.as_mut().as_pin_mut() {
+                if fut.poll_cancel(cx).is_ready() {
+                    elem.set(None);
+                } else {
+                    all_canceled = false;
+                }
+            }
+        }
+
+        if !all_canceled {
+            return Poll::Pending;
+        }
+
+        *this.futs = Box::pin([]);
+        // drop outputs
+        *this.outputs = None;
+        Poll::Ready(())
+    }
+}
+
+#[pin_project(project = EitherProj)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Either<A, B> {
+    Left(#[pin] A),
+    Right(#[pin] B),
+}
+
+impl<A, B> Either<ManuallyDrop<A>, ManuallyDrop<B>> {
+    /// 用左边的future构造`Either`
+    pub fn left_future(a: A) -> Self {
+        Either::Left(ManuallyDrop::new(a))
+    }
+
+    /// 用右边的future构造`Either`
+    pub fn right_future(b: B) -> Self {
+        Either::Right(ManuallyDrop::new(b))
+    }
+}
+
+/// 两边的Output相同时，保存`ManuallyDrop`的`Either`本身也是abort safe future，
+/// 可以在同一个`then`的闭包里返回不同类型的future
+///
+/// ```
+/// use abort_safe_future::{ready, AbortSafeFutureExt, Either};
+/// use abort_safe_future::executor::block_on;
+///
+/// let branch = |x: u32| {
+///     ready(x).then(|x| {
+///         if x % 2 == 0 {
+///             Either::left_future(ready(x / 2))
+///         } else {
+///             Either::right_future(ready(x).map(|x| x * 3 + 1))
+///         }
+///     })
+/// };
+/// assert_eq!(block_on(branch(4)), 2);
+/// assert_eq!(block_on(branch(3)), 10);
+/// ```
+impl<A, B> AbortSafeFuture for Either<ManuallyDrop<A>, ManuallyDrop<B>>
+where
+    A: AbortSafeFuture,
+    B: AbortSafeFuture<Output = A::Output>,
+{
+    type Output = A::Output;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match pin_manually_drop_as_mut(&mut self).project() {
+            EitherProj::Left(a) => a.poll(cx),
+            EitherProj::Right(b) => b.poll(cx),
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        match pin_manually_drop_as_mut(&mut self).project() {
+            EitherProj::Left(a) => a.poll_cancel(cx),
+            EitherProj::Right(b) => b.poll_cancel(cx),
+        }
+    }
+}
+
+/// 同时执行两个future，返回先完成的那一边的结果，另一边会在返回之前被取消。
+///
+/// 每次都先`poll`左边，两边同时就绪时返回左边的结果，右边会被取消。
+///
+/// 回收顺序：先完成的一边在完成时回收，另一边在返回结果之前被取消；取消`Select`时先取消左边，再取消右边。
+///
+/// ```
+/// use abort_safe_future::{pending, ready, select, Either};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::testing::DropOrderRecorder;
+///
+/// let recorder = DropOrderRecorder::new();
+/// let fut = select(recorder.wrap("loser", pending::<u32>()), recorder.wrap("winner", ready(1)));
+/// assert_eq!(block_on(fut), Either::Right(1));
+/// assert_eq!(recorder.events(), ["winner", "loser"]);
+/// ```
+///
+/// 失败的一边持有的资源也会被回收：
+///
+/// ```
+/// use std::rc::Rc;
+/// use std::task::Poll;
+/// use abort_safe_future::{poll_fn, ready, select, Either};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::testing::checked;
+///
+/// let resource = Rc::new(());
+/// let held = resource.clone();
+/// let (loser, finalized) = checked(poll_fn(move |_| {
+///     let _ = &held;
+///     Poll::<()>::Pending
+/// }));
+/// assert_eq!(block_on(select(loser, ready(1))), Either::Right(1));
+/// assert!(finalized.is_finalized());
+/// assert_eq!(Rc::strong_count(&resource), 1);
+/// ```
+pub fn select<Fut1, Fut2>(fut1: Fut1, fut2: Fut2) -> Select<Fut1, Fut2>
+where
+    Fut1: AbortSafeFuture,
+    Fut2: AbortSafeFuture,
+{
+    Select::new(fut1, fut2)
+}
+
+/// `select`返回的future，每次都先`poll`左边，两边同时就绪时左边胜出
+#[pin_project]
+pub struct Select<Fut1: AbortSafeFuture, Fut2: AbortSafeFuture> {
+    #[pin]
+    fut1: Option<ManuallyDrop<Fut1>>,
+    #[pin]
+    fut2: Option<ManuallyDrop<Fut2>>,
+    output: Option<Either<Fut1::Output, Fut2::Output>>,
+}
+
+impl<Fut1: AbortSafeFuture, Fut2: AbortSafeFuture> Select<Fut1, Fut2> {
+    pub fn new(fut1: Fut1, fut2: Fut2) -> Self {
+        Self {
+            fut1: Some(ManuallyDrop::new(fut1)),
+            fut2: Some(ManuallyDrop::new(fut2)),
+            output: None,
+        }
+    }
+}
+
+impl<Fut1, Fut2> AbortSafeFuture for Select<Fut1, Fut2>
+where
+    Fut1: AbortSafeFuture,
+    Fut2: AbortSafeFuture,
+{
+    type Output = Either<Fut1::Output, Fut2::Output>;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        if this.output.is_none() {
+            if this.fut1.is_none() && this.fut2.is_none() {
+                panic!("Select::poll called after completion or after cancel")
+            }
+
+            if let Some(fut1) = this.fut1.as_mut().as_pin_mut() {
+                if let Poll::Ready(output) = fut1.poll(cx) {
+                    *this.output = Some(Either::Left(output));
+                    this.fut1.set(None);
+                }
+            }
+        }
+
+        if this.output.is_none() {
+            if let Some(fut2) = this.fut2.as_mut().as_pin_mut() {
+                if let Poll::Ready(output) = fut2.poll(cx) {
+                    *this.output = Some(Either::Right(output));
+                    this.fut2.set(None);
+                }
+            }
+        }
+
+        if this.output.is_none() {
+            return Poll::Pending;
+        }
+
+        // cancel the loser before reporting the winner
+        if let Some(fut1) = this.fut1.as_mut().as_pin_mut() {
+            ready!(fut1.poll_cancel(cx));
+            this.fut1.set(None);
+        }
+        if let Some(fut2) = this.fut2.as_mut().as_pin_mut() {
+            ready!(fut2.poll_cancel(cx));
+            this.fut2.set(None);
+        }
+
+        Poll::Ready(this.output.take().unwrap())
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        if let Some(fut1) = this.fut1.as_mut().as_pin_mut() {
+            if fut1.poll_cancel(cx).is_ready() {
+                this.fut1.set(None);
+            }
+        }
+
+        if let Some(fut2) = this.fut2.as_mut().as_pin_mut() {
+            if fut2.poll_cancel(cx).is_ready() {
+                this.fut2.set(None);
+            }
+        }
+
+        if this.fut1.is_some() || this.fut2.is_some() {
+            return Poll::Pending;
+        }
+
+        // drop output
+        *this.output = None;
+        Poll::Ready(())
+    }
+}
+
+
+/// 就是`select`，`Select`本来就先`poll`左边。用这个名字可以在调用处表明依赖这个优先级
+///
+/// ```
+/// use abort_safe_future::{ready, select_biased, Either};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::testing::checked;
+///
+/// let (right, finalized) = checked(ready("right"));
+/// assert_eq!(block_on(select_biased(ready(1), right)), Either::Left(1));
+/// assert!(finalized.is_finalized());
+/// ```
+pub fn select_biased<Fut1, Fut2>(fut1: Fut1, fut2: Fut2) -> Select<Fut1, Fut2>
+where
+    Fut1: AbortSafeFuture,
+    Fut2: AbortSafeFuture,
+{
+    select(fut1, fut2)
+}
+
+/// 和`select`一样，只是两边的Output相同，直接返回先完成的那一边的结果
+///
+/// ```
+/// use abort_safe_future::{pending, race, ready};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::testing::checked;
+///
+/// let (loser, loser_finalized) = checked(pending::<u32>());
+/// let (winner, winner_finalized) = checked(ready(7));
+/// assert_eq!(block_on(race(loser, winner)), 7);
+/// assert!(loser_finalized.is_finalized());
+/// assert!(winner_finalized.is_finalized());
+/// ```
+pub fn race<Fut1, Fut2>(fut1: Fut1, fut2: Fut2) -> Race<Fut1, Fut2>
+where
+    Fut1: AbortSafeFuture,
+    Fut2: AbortSafeFuture<Output = Fut1::Output>,
+{
+    Race::new(fut1, fut2)
+}
+
+#[pin_project]
+pub struct Race<Fut1: AbortSafeFuture, Fut2: AbortSafeFuture> {
+    #[pin]
+    inner: ManuallyDrop<Select<Fut1, Fut2>>,
+}
+
+impl<Fut1: AbortSafeFuture, Fut2: AbortSafeFuture> Race<Fut1, Fut2> {
+    pub fn new(fut1: Fut1, fut2: Fut2) -> Self {
+        Self {
+            inner: ManuallyDrop::new(Select::new(fut1, fut2)),
+        }
+    }
+}
+
+impl<Fut1, Fut2> AbortSafeFuture for Race<Fut1, Fut2>
+where
+    Fut1: AbortSafeFuture,
+    Fut2: AbortSafeFuture<Output = Fut1::Output>,
+{
+    type Output = Fut1::Output;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        // `Select` cancels the loser before it completes
+        match ready!(this.inner.poll(cx)) {
+            Either::Left(output) | Either::Right(output) => Poll::Ready(output),
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        this.inner.poll_cancel(cx)
+    }
+}
+
+/// 同时执行一组future，返回最先完成的那个的结果、它的下标以及剩下还没有完成的future。
+///
+/// 剩下的future不会被取消，仍然被固定在`Pin<Box<ManuallyDrop<_>>>`里交还给调用者，
+/// 由调用者决定继续`poll`还是`poll_cancel`。取消`SelectAll`时会取消所有的future。`iter`不能是空的。
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use abort_safe_future::{pending, ready, select_all, AbortSafeFutureExt};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::testing::{poll_cancel_once, DropOrderRecorder};
+///
+/// let recorder = DropOrderRecorder::new();
+/// let futs = vec![
+///     recorder.wrap("first", pending::<u32>()).boxed(),
+///     recorder.wrap("middle", ready(2)).boxed(),
+///     recorder.wrap("last", pending::<u32>()).boxed(),
+/// ];
+/// let (output, index, mut rest) = block_on(select_all(futs));
+/// assert_eq!((output, index, rest.len()), (2, 1, 2));
+/// assert_eq!(recorder.events(), ["middle"]);
+///
+/// for fut in rest.iter_mut() {
+///     assert!(poll_cancel_once(fut.as_mut()).is_ready());
+/// }
+/// assert_eq!(recorder.events(), ["middle", "first", "last"]);
+/// ```
+pub fn select_all<I>(iter: I) -> SelectAll<I::Item>
+where
+    I: IntoIterator,
+    I::Item: AbortSafeFuture,
+{
+    SelectAll::new(iter.into_iter().collect())
+}
+
+pub struct SelectAll<Fut> {
+    futs: Vec<Option<Pin<Box<ManuallyDrop<Fut>>>>>,
+}
+
+impl<Fut: AbortSafeFuture> SelectAll<Fut> {
+    pub fn new(futs: Vec<Fut>) -> Self {
+        assert!(!futs.is_empty(), "select_all called with an empty list");
+        Self {
+            futs: futs.into_iter().map(|fut| Some(Box::pin(ManuallyDrop::new(fut)))).collect(),
+        }
+    }
+}
+
+impl<Fut: AbortSafeFuture> AbortSafeFuture for SelectAll<Fut> {
+    type Output = (Fut::Output, usize, Vec<Pin<Box<ManuallyDrop<Fut>>>>);
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = pin_manually_drop_as_mut(&mut self).get_mut();
+        if this.futs.is_empty() {
+            panic!("SelectAll::poll called after completion or after cancel")
+        }
+
+        let winner = this.futs.iter_mut().enumerate().find_map(|(i, fut)| {
+            let fut = fut.as_mut()?;
+            match fut.as_mut().poll(cx) {
+                Poll::Ready(output) => Some((i, output)),
+                Poll::Pending => None,
+            }
+        });
+
+        match winner {
+            Some((i, output)) => {
+                // the winner has reclaimed its resources, the rest are handed back still pinned
+                let rest = mem::take(&mut this.futs)
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(j, _)| *j != i)
+                    .filter_map(|(_, fut)| fut)
+                    .collect();
+                Poll::Ready((output, i, rest))
+            }
+            None => Poll::Pending,
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).get_mut();
+
+        let mut all_canceled = true;
+        for slot in this.futs.iter_mut() {
+            if let Some(fut) = slot {
+                if fut.as_mut().poll_cancel(cx).is_ready() {
+                    *slot = None;
+                } else {
+                    all_canceled = false;
+                }
+            }
+        }
+
+        if !all_canceled {
+            return Poll::Pending;
+        }
+
+        this.futs = Vec::new();
+        Poll::Ready(())
+    }
+}
+
+pub fn poll_fn<T, F>(f: F) -> PollFn<F>
+where
+    F: FnMut(&mut Context<'_>) -> Poll<T>,
+{
+    PollFn::new(f)
+}
+
+/// 由闭包构造的abort safe future。
+///
+/// 闭包本身没有异步的清理工作，所以`poll_cancel`只会直接析构闭包（包括它捕获的资源），然后返回`Poll::Ready`。
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use std::task::Poll;
+/// use abort_safe_future::poll_fn;
+/// use abort_safe_future::testing::poll_once;
+///
+/// // counts to two, one step per poll
+/// let mut count = 0;
+/// let mut fut = Box::pin(ManuallyDrop::new(poll_fn(move |_| {
+///     count += 1;
+///     if count < 2 { Poll::Pending } else { Poll::Ready(count) }
+/// })));
+/// assert!(poll_once(fut.as_mut()).is_pending());
+/// assert_eq!(poll_once(fut.as_mut()), Poll::Ready(2));
+/// ```
+#[pin_project]
+pub struct PollFn<F> {
+    f: Option<F>,
+}
+
+impl<F> PollFn<F> {
+    pub fn new(f: F) -> Self {
+        Self { f: Some(f) }
+    }
+}
+
+impl<T, F> AbortSafeFuture for PollFn<F>
+where
+    F: FnMut(&mut Context<'_>) -> Poll<T>,
+{
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        let f = match this.f {
+            Some(f) => f,
+            None => panic!("PollFn::poll called after completion or after cancel"),
+        };
+
+        let output = ready!(f(cx));
+        // drop closure
+        *this.f = None;
+        Poll::Ready(output)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        // drop closure
+        *this.f = None;
+        Poll::Ready(())
+    }
+}
+
+pub fn lazy<T, F>(f: F) -> Lazy<F>
+where
+    F: FnOnce(&mut Context<'_>) -> T,
+{
+    Lazy::new(f)
+}
+
+/// 第一次`poll`时才调用闭包。在此之前取消的话，闭包不会被调用，只会被析构。
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::cell::Cell;
+/// use std::mem::ManuallyDrop;
+/// use abort_safe_future::lazy;
+/// use abort_safe_future::testing::poll_cancel_once;
+///
+/// let called = Cell::new(false);
+/// let mut fut = Box::pin(ManuallyDrop::new(lazy(|_| called.set(true))));
+/// assert!(poll_cancel_once(fut.as_mut()).is_ready());
+/// assert!(!called.get());
+/// ```
+#[pin_project]
+pub struct Lazy<F> {
+    f: Option<F>,
+}
+
+impl<F> Lazy<F> {
+    pub fn new(f: F) -> Self {
+        Self { f: Some(f) }
+    }
+}
+
+impl<T, F> AbortSafeFuture for Lazy<F>
+where
+    F: FnOnce(&mut Context<'_>) -> T,
+{
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        let f = this.f.take().expect("Lazy::poll called after completion or after cancel");
+        Poll::Ready(f(cx))
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        // drop closure
+        *this.f = None;
+        Poll::Ready(())
+    }
+}
+
+/// `AbortSafeFutureExt::by_ref`返回的future，借用而不是拥有`Fut`。
+///
+/// `poll`转发给借用的future。`poll_cancel`只释放借用，不会取消`Fut`，
+/// 无论`ByRef`完成还是被取消，`Fut`的所有者都要自己调用`Fut::poll_cancel`回收它。
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::cell::Cell;
+/// use std::mem::ManuallyDrop;
+/// use abort_safe_future::{pending, ready, AbortSafeFutureExt};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::testing::{poll_cancel_once, poll_once};
+///
+/// let mut fut = Box::pin(ManuallyDrop::new(ready(1)));
+/// assert_eq!(block_on(fut.as_mut().by_ref()), 1);
+/// // the owner still runs the cleanup, which is a harmless `Ready` after completion
+/// assert!(poll_cancel_once(fut.as_mut()).is_ready());
+///
+/// let canceled = Cell::new(false);
+/// let mut fut = Box::pin(ManuallyDrop::new(pending::<u32>().inspect_cancel(|| canceled.set(true))));
+/// let mut by_ref = Box::pin(ManuallyDrop::new(fut.as_mut().by_ref()));
+/// assert!(poll_once(by_ref.as_mut()).is_pending());
+/// assert!(poll_cancel_once(by_ref.as_mut()).is_ready());
+/// assert!(!canceled.get());
+/// assert!(poll_cancel_once(fut.as_mut()).is_ready());
+/// assert!(canceled.get());
+/// ```
+///
+/// 普通的借用不是abort safe future，必须显式地使用`by_ref`：
+///
+/// ```compile_fail
+/// use std::mem::ManuallyDrop;
+/// use abort_safe_future::ready;
+/// use abort_safe_future::executor::block_on;
+///
+/// let mut fut = ManuallyDrop::new(ready(1));
+/// block_on(&mut fut);
+/// ```
+pub struct ByRef<'a, Fut: ?Sized> {
+    fut: Option<Pin<&'a mut ManuallyDrop<Fut>>>,
+}
+
+impl<'a, Fut: ?Sized> ByRef<'a, Fut> {
+    pub fn new(fut: Pin<&'a mut ManuallyDrop<Fut>>) -> Self {
+        Self { fut: Some(fut) }
+    }
+}
+
+impl<Fut: AbortSafeFuture + ?Sized> AbortSafeFuture for ByRef<'_, Fut> {
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let fut = match &mut self.fut {
+            Some(fut) => fut,
+            None => panic!("ByRef::poll called after completion or after cancel"),
+        };
+
+        let output = ready!(fut.as_mut().poll(cx));
+        self.fut = None;
+        Poll::Ready(output)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+        // only release the borrow, the owner of the future is responsible for cancelling it
+        self.fut = None;
+        Poll::Ready(())
+    }
+}
+
+/// 完成之后再`poll`不会panic，而是一直返回`Poll::Pending`。
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use std::task::Poll;
+/// use abort_safe_future::{ready, AbortSafeFutureExt};
+/// use abort_safe_future::testing::poll_once;
+///
+/// let mut fut = Box::pin(ManuallyDrop::new(ready(1).fuse()));
+/// assert_eq!(poll_once(fut.as_mut()), Poll::Ready(1));
+/// for _ in 0..3 {
+///     assert!(poll_once(fut.as_mut()).is_pending());
+/// }
+/// assert!(fut.is_terminated());
+/// ```
+#[pin_project]
+pub struct Fuse<Fut> {
+    #[pin]
+    inner: Option<ManuallyDrop<Fut>>,
+}
+
+impl<Fut> Fuse<Fut> {
+    pub fn new(fut: Fut) -> Self {
+        Self {
+            inner: Some(ManuallyDrop::new(fut)),
+        }
+    }
+
+    /// inner future是否已经完成或者被取消
+    pub fn is_terminated(&self) -> bool {
+        self.inner.is_none()
+    }
+}
+
+impl<Fut: AbortSafeFuture> AbortSafeFuture for Fuse<Fut> {
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(fut) = this.inner.as_mut().as_pin_mut() {
+            let output = ready!(fut.poll(cx));
+            this.inner.set(None);
+            Poll::Ready(output)
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(fut) = this.inner.as_mut().as_pin_mut() {
+            ready!(fut.poll_cancel(cx));
+            this.inner.set(None);
+        }
+
+        Poll::Ready(())
+    }
+}
+
+/// 捕获inner future在`poll`中的panic。
+///
+/// inner panic之后，会在返回`Err`之前继续调用inner的`poll_cancel`回收资源，
+/// 如果`poll_cancel`也panic了，则放弃回收（可能内存泄漏）。
+///
+/// ```
+/// use std::cell::Cell;
+/// use std::task::Poll;
+/// use abort_safe_future::{join, pending, poll_fn, AbortSafeFutureExt};
+/// use abort_safe_future::executor::block_on;
+///
+/// let reclaimed = Cell::new(false);
+/// let guard = pending::<()>().inspect_cancel(|| reclaimed.set(true));
+/// let mut polls = 0;
+/// let panicky = poll_fn(move |cx| -> Poll<()> {
+///     polls += 1;
+///     if polls == 2 {
+///         panic!("second poll");
+///     }
+///     cx.waker().wake_by_ref();
+///     Poll::Pending
+/// });
+///
+/// assert!(block_on(join(guard, panicky).catch_unwind()).is_err());
+/// assert!(reclaimed.get());
+/// ```
+#[pin_project]
+pub struct CatchUnwind<Fut> {
+    #[pin]
+    inner: Option<ManuallyDrop<Fut>>,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl<Fut> CatchUnwind<Fut> {
+    pub fn new(fut: Fut) -> Self {
+        Self {
+            inner: Some(ManuallyDrop::new(fut)),
+            panic: None,
+        }
+    }
+}
+
+impl<Fut: AbortSafeFuture> AbortSafeFuture for CatchUnwind<Fut> {
+    type Output = Result<Fut::Output, Box<dyn Any + Send>>;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        if this.panic.is_none() {
+            let fut = match this.inner.as_mut().as_pin_mut() {
+                Some(fut) => fut,
+                None => panic!("CatchUnwind::poll called after completion or after cancel"),
+            };
+
+            match catch_unwind(AssertUnwindSafe(|| fut.poll(cx))) {
+                Ok(Poll::Pending) => return Poll::Pending,
+                Ok(Poll::Ready(output)) => {
+                    this.inner.set(None);
+                    return Poll::Ready(Ok(output));
+                }
+                Err(panic) => *this.panic = Some(panic),
+            }
+        }
+
+        // inner panicked, try to reclaim its resources
+        if let Some(fut) = this.inner.as_mut().as_pin_mut() {
+            if let Ok(Poll::Pending) = catch_unwind(AssertUnwindSafe(|| fut.poll_cancel(cx))) {
+                return Poll::Pending;
+            }
+            this.inner.set(None);
+        }
+
+        Poll::Ready(Err(this.panic.take().unwrap()))
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(fut) = this.inner.as_mut().as_pin_mut() {
+            if let Ok(Poll::Pending) = catch_unwind(AssertUnwindSafe(|| fut.poll_cancel(cx))) {
+                return Poll::Pending;
+            }
+            this.inner.set(None);
+        }
+
+        // drop panic payload
+        *this.panic = None;
+        Poll::Ready(())
+    }
+}
+
+/// 把abort safe future转换成std的Future。
+///
+/// std的Future只能同步地析构，所以在inner完成之前被析构的话，
+/// 会在析构函数中阻塞当前线程，直到inner的`poll_cancel`返回`Poll::Ready`。
+///
+/// 如果inner的取消需要由同一个线程上的执行器驱动的其他任务才能完成，例如等待单线程执行器中另一个任务的消息，
+/// 析构函数会一直阻塞这个线程，造成死锁。这种情况下应该在析构之前自己把`poll_cancel`驱动到完成。
+///
+/// ```
+/// use std::cell::Cell;
+/// use abort_safe_future::{pending, ready, AbortSafeFutureExt};
+///
+/// let fut = ready(1).then(|x| ready(x + 1)).into_std_future();
+/// assert_eq!(futures::executor::block_on(async { fut.await * 10 }), 20);
+///
+/// // dropped before completion, the inner future is canceled in `drop`
+/// let canceled = Cell::new(false);
+/// let fut = pending::<u32>().inspect_cancel(|| canceled.set(true)).into_std_future();
+/// let output = futures::executor::block_on(async { futures::poll!(Box::pin(fut)) });
+/// assert!(output.is_pending());
+/// assert!(canceled.get());
+/// ```
+#[pin_project(PinnedDrop)]
+pub struct IntoStdFuture<Fut: AbortSafeFuture> {
+    #[pin]
+    inner: ManuallyDrop<Fut>,
+    done: bool,
+}
+
+impl<Fut: AbortSafeFuture> IntoStdFuture<Fut> {
+    pub fn new(fut: Fut) -> Self {
+        Self {
+            inner: ManuallyDrop::new(fut),
+            done: false,
+        }
+    }
+}
+
+impl<Fut: AbortSafeFuture> Future for IntoStdFuture<Fut> {
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        if *this.done {
+            panic!("IntoStdFuture::poll called after completion")
+        }
+
+        let output = ready!(this.inner.poll(cx));
+        *this.done = true;
+        Poll::Ready(output)
+    }
+}
+
+#[pinned_drop]
+impl<Fut: AbortSafeFuture> PinnedDrop for IntoStdFuture<Fut> {
+    fn drop(self: Pin<&mut Self>) {
+        let this = self.project();
+        if !*this.done {
+            block_on_cancel(this.inner);
+        }
+    }
+}
+
+/// 可以被多个handle同时等待的future，每个handle都会得到一份结果的clone。
+///
+/// inner只会被驱动一次：由第一个发现其完成的handle把结果保存下来。
+/// 取消时只有最后一个handle会取消inner，其他handle只释放自己的引用。
+/// 还持有引用的handle数量在`state`的锁里维护，所以多个线程同时取消也只会有一个handle取消inner。
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::cell::Cell;
+/// use std::mem::ManuallyDrop;
+/// use std::task::Poll;
+/// use abort_safe_future::{pending, poll_fn, AbortSafeFutureExt};
+/// use abort_safe_future::executor::block_on_all;
+/// use abort_safe_future::testing::{checked, poll_cancel_once, poll_once};
+///
+/// let mut polls = 0;
+/// let (fut, finalized) = checked(poll_fn(move |cx| {
+///     polls += 1;
+///     if polls < 3 {
+///         cx.waker().wake_by_ref();
+///         return Poll::Pending;
+///     }
+///     Poll::Ready(String::from("shared"))
+/// }));
+/// let first = fut.shared();
+/// let second = first.clone();
+/// assert_eq!(block_on_all(vec![first, second]), ["shared", "shared"]);
+/// assert!(finalized.is_finalized());
+///
+/// let cancels = Cell::new(0);
+/// let first = pending::<u32>().inspect_cancel(|| cancels.set(cancels.get() + 1)).shared();
+/// let second = first.clone();
+/// let mut first = Box::pin(ManuallyDrop::new(first));
+/// let mut second = Box::pin(ManuallyDrop::new(second));
+/// assert!(poll_once(first.as_mut()).is_pending());
+/// assert!(poll_cancel_once(first.as_mut()).is_ready());
+/// assert_eq!(cancels.get(), 0);
+/// assert!(poll_cancel_once(second.as_mut()).is_ready());
+/// assert_eq!(cancels.get(), 1);
+/// ```
+pub struct Shared<Fut: AbortSafeFuture> {
+    inner: Option<Arc<SharedInner<Fut>>>,
+    id: usize,
+}
+
+struct SharedInner<Fut: AbortSafeFuture> {
+    state: Mutex<SharedSlot<Fut>>,
+    notifier: Arc<Notifier>,
+    next_id: AtomicUsize,
+}
+
+struct SharedSlot<Fut: AbortSafeFuture> {
+    state: SharedState<Fut>,
+    // handles that still hold a reference
+    handles: usize,
+}
+
+enum SharedState<Fut: AbortSafeFuture> {
+    Running(Pin<Box<ManuallyDrop<Fut>>>),
+    Done(Fut::Output),
+    Canceled,
+}
+
+/// wakes every handle waiting on the shared future
+struct Notifier {
+    wakers: Mutex<HashMap<usize, Waker>>,
+}
+
+impl Wake for Notifier {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        for (_, waker) in self.wakers.lock().unwrap().drain() {
+            waker.wake();
+        }
+    }
+}
+
+impl<Fut: AbortSafeFuture> Shared<Fut> {
+    pub fn new(fut: Fut) -> Self {
+        let inner = SharedInner {
+            state: Mutex::new(SharedSlot {
+                state: SharedState::Running(Box::pin(ManuallyDrop::new(fut))),
+                handles: 1,
+            }),
+            notifier: Arc::new(Notifier {
+                wakers: Mutex::new(HashMap::new()),
+            }),
+            next_id: AtomicUsize::new(1),
+        };
+
+        Self {
+            inner: Some(Arc::new(inner)),
+            id: 0,
+        }
+    }
+}
+
+impl<Fut: AbortSafeFuture> Clone for Shared<Fut> {
+    fn clone(&self) -> Self {
+        let id = match &self.inner {
+            Some(inner) => {
+                inner.state.lock().unwrap().handles += 1;
+                inner.next_id.fetch_add(1, Ordering::Relaxed)
+            }
+            None => 0,
+        };
+
+        Self {
+            inner: self.inner.clone(),
+            id,
+        }
+    }
+}
+
+impl<Fut> AbortSafeFuture for Shared<Fut>
+where
+    Fut: AbortSafeFuture,
+    Fut::Output: Clone,
+{
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let id = self.id;
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => panic!("Shared::poll called after completion or after cancel"),
+        };
+
+        let mut slot = inner.state.lock().unwrap();
+        if let SharedState::Running(fut) = &mut slot.state {
+            inner.notifier.wakers.lock().unwrap().insert(id, cx.waker().clone());
+            let waker = Waker::from(inner.notifier.clone());
+            let output = ready!(fut.as_mut().poll(&mut Context::from_waker(&waker)));
+            slot.state = SharedState::Done(output);
+            // wake the other handles
+            inner.notifier.wake_by_ref();
+        }
+
+        let output = match &slot.state {
+            SharedState::Done(output) => output.clone(),
+            SharedState::Running(_) => unreachable!(),
+            SharedState::Canceled => unreachable!("Shared canceled while handles are alive"),
+        };
+        slot.handles -= 1;
+        drop(slot);
+
+        inner.notifier.wakers.lock().unwrap().remove(&id);
+        // release the shared reference
+        self.inner = None;
+        Poll::Ready(output)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let id = self.id;
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return Poll::Ready(()),
+        };
+
+        let mut slot = inner.state.lock().unwrap();
+        // only the last handle cancels the inner future, it stays counted until the cancel completes
+        if slot.handles == 1 {
+            if let SharedState::Running(fut) = &mut slot.state {
+                ready!(fut.as_mut().poll_cancel(cx));
+            }
+            // also drops a saved output
+            slot.state = SharedState::Canceled;
+        }
+        slot.handles -= 1;
+        drop(slot);
+
+        inner.notifier.wakers.lock().unwrap().remove(&id);
+        // release the shared reference
+        self.inner = None;
+        Poll::Ready(())
+    }
+}
+
+/// `Fut1`返回`Ok`时，用`F`得到`Fut2`并执行；返回`Err`时直接返回，`F`不会被调用。
+///
+/// ```
+/// use std::cell::Cell;
+/// use abort_safe_future::{ready, AbortSafeFutureExt};
+/// use abort_safe_future::executor::block_on;
+///
+/// let fut = ready(Ok::<u32, ()>(1)).and_then(|x| ready(Ok(x + 1)));
+/// assert_eq!(block_on(fut), Ok(2));
+///
+/// let called = Cell::new(false);
+/// let fut = ready(Err::<u32, &str>("boom")).and_then(|x| {
+///     called.set(true);
+///     ready(Ok::<u32, &str>(x + 1))
+/// });
+/// assert_eq!(block_on(fut), Err("boom"));
+/// assert!(!called.get());
+/// ```
+#[pin_project]
+pub struct AndThen<Fut1, Fut2, F, M = AbortSafeMarker> {
+    #[pin]
+    inner: ThenInner<Fut1, Fut2>,
+    f: Option<F>,
+    _marker: PhantomData<fn() -> M>,
+}
+
+impl<Fut1, Fut2, F, M> AndThen<Fut1, Fut2, F, M> {
+    pub fn new(fut1: Fut1, f: F) -> Self {
+        Self {
+            inner: ThenInner::Fut1(ManuallyDrop::new(fut1)),
+            f: Some(f),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<Fut1, Fut2, F, B, M, T, U, E> AbortSafeFuture for AndThen<Fut1, Fut2, F, M>
+where
+    Fut1: AbortSafeFuture<Output = Result<T, E>>,
+    Fut2: AbortSafeFuture<Output = Result<U, E>>,
+    F: FnOnce(T) -> B,
+    B: IntoAbortSafeFuture<M, Future = Fut2>,
+{
+    type Output = Result<U, E>;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        loop {
+            let inner = this.inner.as_mut().project();
+            match inner {
+                ThenProj::Fut1(fut1) => match ready!(fut1.poll(cx)) {
+                    Ok(t) => {
+                        let f = this.f.take().unwrap();
+                        this.inner.set(ThenInner::Fut2(ManuallyDrop::new(f(t).into_abort_safe())));
+                    }
+                    Err(e) => {
+                        // short circuit, `Fut2` is never constructed
+                        *this.f = None;
+                        this.inner.set(ThenInner::Done);
+                        return Poll::Ready(Err(e));
+                    }
+                },
+                ThenProj::Fut2(fut2) => {
+                    let output = ready!(fut2.poll(cx));
+                    this.inner.set(ThenInner::Done);
+                    return Poll::Ready(output);
+                }
+                ThenProj::Done => panic!("AndThen::poll called after completion"),
+                ThenProj::Canceled => panic!("AndThen::poll called after cancel"),
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        let inner = this.inner.as_mut().project();
+        match inner {
+            ThenProj::Fut1(fut1) => {
+                ready!(fut1.poll_cancel(cx));
+                // drop closure
+                *this.f = None;
+            }
+            ThenProj::Fut2(fut2) => {
+                ready!(fut2.poll_cancel(cx));
+            }
+            ThenProj::Done | ThenProj::Canceled => {}
+        }
+
+        this.inner.set(ThenInner::Canceled);
+        Poll::Ready(())
+    }
+}
+
+/// `Fut1`返回`Err`时，用`F`得到`Fut2`并执行；返回`Ok`时直接返回，`F`不会被调用。
+///
+/// ```
+/// use abort_safe_future::{ready, AbortSafeFutureExt};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::testing::checked;
+///
+/// let (first, finalized) = checked(ready(Err::<u32, &str>("boom")));
+/// let fut = first.or_else(|e| ready(Ok::<u32, ()>(e.len() as u32)));
+/// assert_eq!(block_on(fut), Ok(4));
+/// assert!(finalized.is_finalized());
+/// ```
+#[pin_project]
+pub struct OrElse<Fut1, Fut2, F, M = AbortSafeMarker> {
+    #[pin]
+    inner: ThenInner<Fut1, Fut2>,
+    f: Option<F>,
+    _marker: PhantomData<fn() -> M>,
+}
+
+impl<Fut1, Fut2, F, M> OrElse<Fut1, Fut2, F, M> {
+    pub fn new(fut1: Fut1, f: F) -> Self {
+        Self {
+            inner: ThenInner::Fut1(ManuallyDrop::new(fut1)),
+            f: Some(f),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<Fut1, Fut2, F, B, M, T, E, E2> AbortSafeFuture for OrElse<Fut1, Fut2, F, M>
+where
+    Fut1: AbortSafeFuture<Output = Result<T, E>>,
+    Fut2: AbortSafeFuture<Output = Result<T, E2>>,
+    F: FnOnce(E) -> B,
+    B: IntoAbortSafeFuture<M, Future = Fut2>,
+{
+    type Output = Result<T, E2>;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        loop {
+            let inner = this.inner.as_mut().project();
+            match inner {
+                ThenProj::Fut1(fut1) => match ready!(fut1.poll(cx)) {
+                    Ok(t) => {
+                        // short circuit, `Fut2` is never constructed
+                        *this.f = None;
+                        this.inner.set(ThenInner::Done);
+                        return Poll::Ready(Ok(t));
+                    }
+                    Err(e) => {
+                        let f = this.f.take().unwrap();
+                        this.inner.set(ThenInner::Fut2(ManuallyDrop::new(f(e).into_abort_safe())));
+                    }
+                },
+                ThenProj::Fut2(fut2) => {
+                    let output = ready!(fut2.poll(cx));
+                    this.inner.set(ThenInner::Done);
+                    return Poll::Ready(output);
+                }
+                ThenProj::Done => panic!("OrElse::poll called after completion"),
+                ThenProj::Canceled => panic!("OrElse::poll called after cancel"),
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        let inner = this.inner.as_mut().project();
+        match inner {
+            ThenProj::Fut1(fut1) => {
+                ready!(fut1.poll_cancel(cx));
+                // drop closure
+                *this.f = None;
+            }
+            ThenProj::Fut2(fut2) => {
+                ready!(fut2.poll_cancel(cx));
+            }
+            ThenProj::Done | ThenProj::Canceled => {}
+        }
+
+        this.inner.set(ThenInner::Canceled);
+        Poll::Ready(())
+    }
+}
+
+/// 用`F`转换`Ok`中的值，`Err`原样返回。
+///
+/// ```
+/// use abort_safe_future::{ready, AbortSafeFutureExt};
+/// use abort_safe_future::executor::block_on;
+///
+/// assert_eq!(block_on(ready(Ok::<u32, &str>(1)).map_ok(|x| x + 1)), Ok(2));
+/// assert_eq!(block_on(ready(Err::<u32, &str>("boom")).map_ok(|x| x + 1)), Err("boom"));
+/// ```
+#[pin_project]
+pub struct MapOk<Fut, F> {
+    #[pin]
+    inner: ManuallyDrop<Fut>,
+    f: Option<F>,
+}
+
+impl<Fut, F> MapOk<Fut, F> {
+    pub fn new(fut: Fut, f: F) -> Self {
+        Self {
+            inner: ManuallyDrop::new(fut),
+            f: Some(f),
+        }
+    }
+}
+
+impl<Fut, F, T, U, E> AbortSafeFuture for MapOk<Fut, F>
+where
+    Fut: AbortSafeFuture<Output = Result<T, E>>,
+    F: FnOnce(T) -> U,
+{
+    type Output = Result<U, E>;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        if this.f.is_none() {
+            panic!("MapOk::poll called after completion or after cancel")
+        }
+
+        let output = ready!(this.inner.poll(cx));
+        // the closure is dropped on `Err`
+        let f = this.f.take().unwrap();
+        Poll::Ready(output.map(f))
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        if this.f.is_some() {
+            ready!(this.inner.poll_cancel(cx));
+            // drop closure
+            *this.f = None;
+        }
+
+        Poll::Ready(())
+    }
+}
+
+/// 用`F`转换`Err`中的值，`Ok`原样返回。
+///
+/// ```
+/// use abort_safe_future::{ready, AbortSafeFutureExt};
+/// use abort_safe_future::executor::block_on;
+///
+/// assert_eq!(block_on(ready(Ok::<u32, &str>(1)).map_err(|e| e.len())), Ok(1));
+/// assert_eq!(block_on(ready(Err::<u32, &str>("boom")).map_err(|e| e.len())), Err(4));
+/// ```
+#[pin_project]
+pub struct MapErr<Fut, F> {
+    #[pin]
+    inner: ManuallyDrop<Fut>,
+    f: Option<F>,
+}
+
+impl<Fut, F> MapErr<Fut, F> {
+    pub fn new(fut: Fut, f: F) -> Self {
+        Self {
+            inner: ManuallyDrop::new(fut),
+            f: Some(f),
+        }
+    }
+}
+
+impl<Fut, F, T, E, E2> AbortSafeFuture for MapErr<Fut, F>
+where
+    Fut: AbortSafeFuture<Output = Result<T, E>>,
+    F: FnOnce(E) -> E2,
+{
+    type Output = Result<T, E2>;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        if this.f.is_none() {
+            panic!("MapErr::poll called after completion or after cancel")
+        }
+
+        let output = ready!(this.inner.poll(cx));
+        // the closure is dropped on `Ok`
+        let f = this.f.take().unwrap();
+        Poll::Ready(output.map_err(f))
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        if this.f.is_some() {
+            ready!(this.inner.poll_cancel(cx));
+            // drop closure
+            *this.f = None;
+        }
+
+        Poll::Ready(())
+    }
+}
+
+/// 结果为`Ok`时用它的引用调用`f`，不改变结果
+#[pin_project]
+pub struct InspectOk<Fut, F> {
+    #[pin]
+    inner: ManuallyDrop<Fut>,
+    f: Option<F>,
+}
+
+impl<Fut, F> InspectOk<Fut, F> {
+    pub fn new(fut: Fut, f: F) -> Self {
+        Self {
+            inner: ManuallyDrop::new(fut),
+            f: Some(f),
+        }
+    }
+}
+
+impl<Fut, F, T, E> AbortSafeFuture for InspectOk<Fut, F>
+where
+    Fut: AbortSafeFuture<Output = Result<T, E>>,
+    F: FnOnce(&T),
+{
+    type Output = Result<T, E>;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        if this.f.is_none() {
+            panic!("InspectOk::poll called after completion or after cancel")
+        }
+
+        let output = ready!(this.inner.poll(cx));
+        // the closure is dropped on `Err`
+        let f = this.f.take().unwrap();
+        if let Ok(value) = &output {
+            f(value);
+        }
+        Poll::Ready(output)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        if this.f.is_some() {
+            ready!(this.inner.poll_cancel(cx));
+            // drop closure
+            *this.f = None;
+        }
+
+        Poll::Ready(())
+    }
+}
+
+/// 结果为`Err`时用它的引用调用`f`，不改变结果
+#[pin_project]
+pub struct InspectErr<Fut, F> {
+    #[pin]
+    inner: ManuallyDrop<Fut>,
+    f: Option<F>,
+}
+
+impl<Fut, F> InspectErr<Fut, F> {
+    pub fn new(fut: Fut, f: F) -> Self {
+        Self {
+            inner: ManuallyDrop::new(fut),
+            f: Some(f),
+        }
+    }
+}
+
+impl<Fut, F, T, E> AbortSafeFuture for InspectErr<Fut, F>
+where
+    Fut: AbortSafeFuture<Output = Result<T, E>>,
+    F: FnOnce(&E),
+{
+    type Output = Result<T, E>;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        if this.f.is_none() {
+            panic!("InspectErr::poll called after completion or after cancel")
+        }
+
+        let output = ready!(this.inner.poll(cx));
+        // the closure is dropped on `Ok`
+        let f = this.f.take().unwrap();
+        if let Err(err) = &output {
+            f(err);
+        }
+        Poll::Ready(output)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        if this.f.is_some() {
+            ready!(this.inner.poll_cancel(cx));
+            // drop closure
+            *this.f = None;
+        }
+
+        Poll::Ready(())
+    }
+}
+
+/// `Fut`返回`Err`时，用`F`从错误得到一个结果。
+///
+/// ```
+/// use abort_safe_future::{ready, AbortSafeFutureExt};
+/// use abort_safe_future::executor::block_on;
+///
+/// assert_eq!(block_on(ready(Err::<u32, &str>("boom")).unwrap_or_else(|_| 0)), 0);
+/// assert_eq!(block_on(ready(Ok::<u32, &str>(7)).unwrap_or_else(|_| 0)), 7);
+/// ```
+#[pin_project]
+pub struct UnwrapOrElse<Fut, F> {
+    #[pin]
+    inner: ManuallyDrop<Fut>,
+    f: Option<F>,
+}
+
+impl<Fut, F> UnwrapOrElse<Fut, F> {
+    pub fn new(fut: Fut, f: F) -> Self {
+        Self {
+            inner: ManuallyDrop::new(fut),
+            f: Some(f),
+        }
+    }
+}
+
+impl<Fut, F, T, E> AbortSafeFuture for UnwrapOrElse<Fut, F>
+where
+    Fut: AbortSafeFuture<Output = Result<T, E>>,
+    F: FnOnce(E) -> T,
+{
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        if this.f.is_none() {
+            panic!("UnwrapOrElse::poll called after completion or after cancel")
+        }
+
+        let output = ready!(this.inner.poll(cx));
+        // the closure is dropped on `Ok`
+        let f = this.f.take().unwrap();
+        Poll::Ready(output.unwrap_or_else(f))
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        if this.f.is_some() {
+            ready!(this.inner.poll_cancel(cx));
+            // drop closure
+            *this.f = None;
+        }
+
+        Poll::Ready(())
+    }
+}
+
+/// 先执行`Fut`，再执行它返回的future。
+///
+/// ```
+/// use abort_safe_future::{ready, AbortSafeFutureExt};
+/// use abort_safe_future::executor::block_on;
+///
+/// assert_eq!(block_on(ready(ready(5)).flatten()), 5);
+/// ```
+#[pin_project]
+pub struct Flatten<Fut: AbortSafeFuture> {
+    #[pin]
+    inner: ThenInner<Fut, Fut::Output>,
+}
+
+impl<Fut: AbortSafeFuture> Flatten<Fut> {
+    pub fn new(fut: Fut) -> Self {
+        Self {
+            inner: ThenInner::Fut1(ManuallyDrop::new(fut)),
+        }
+    }
+}
+
+impl<Fut> AbortSafeFuture for Flatten<Fut>
+where
+    Fut: AbortSafeFuture,
+    Fut::Output: AbortSafeFuture,
+{
+    type Output = <Fut::Output as AbortSafeFuture>::Output;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        loop {
+            let inner = this.inner.as_mut().project();
+            match inner {
+                ThenProj::Fut1(outer) => {
+                    let fut = ready!(outer.poll(cx));
+                    this.inner.set(ThenInner::Fut2(ManuallyDrop::new(fut)));
+                }
+                ThenProj::Fut2(fut) => {
+                    let output = ready!(fut.poll(cx));
+                    this.inner.set(ThenInner::Done);
+                    return Poll::Ready(output);
+                }
+                ThenProj::Done => panic!("Flatten::poll called after completion"),
+                ThenProj::Canceled => panic!("Flatten::poll called after cancel"),
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        let inner = this.inner.as_mut().project();
+        match inner {
+            ThenProj::Fut1(outer) => ready!(outer.poll_cancel(cx)),
+            // the outer future has already completed
+            ThenProj::Fut2(fut) => ready!(fut.poll_cancel(cx)),
+            ThenProj::Done | ThenProj::Canceled => {}
+        }
+
+        this.inner.set(ThenInner::Canceled);
+        Poll::Ready(())
+    }
+}
+
+/// `Fut`的结果为`Ok(fut2)`时继续执行`fut2`，为`Err`时直接返回这个错误。
+///
+/// 取消时只取消正在执行的那一个。
+///
+/// ```
+/// use abort_safe_future::{ready, AbortSafeFutureExt};
+/// use abort_safe_future::executor::block_on;
+///
+/// let fut = ready(Ok::<_, &str>(ready(Ok::<u32, &str>(1)))).try_flatten();
+/// assert_eq!(block_on(fut), Ok(1));
+///
+/// let fut = ready(Err::<abort_safe_future::combinator::Ready<Result<u32, &str>>, _>("outer")).try_flatten();
+/// assert_eq!(block_on(fut), Err("outer"));
+/// ```
+#[pin_project]
+pub struct TryFlatten<Fut, Fut2> {
+    #[pin]
+    inner: ThenInner<Fut, Fut2>,
+}
+
+impl<Fut, Fut2> TryFlatten<Fut, Fut2> {
+    pub fn new(fut: Fut) -> Self {
+        Self {
+            inner: ThenInner::Fut1(ManuallyDrop::new(fut)),
+        }
+    }
+}
+
+impl<Fut, Fut2, T, E> AbortSafeFuture for TryFlatten<Fut, Fut2>
+where
+    Fut: AbortSafeFuture<Output = Result<Fut2, E>>,
+    Fut2: AbortSafeFuture<Output = Result<T, E>>,
+{
+    type Output = Result<T, E>;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        loop {
+            let inner = this.inner.as_mut().project();
+            match inner {
+                ThenProj::Fut1(outer) => match ready!(outer.poll(cx)) {
+                    Ok(fut) => this.inner.set(ThenInner::Fut2(ManuallyDrop::new(fut))),
+                    Err(e) => {
+                        this.inner.set(ThenInner::Done);
+                        return Poll::Ready(Err(e));
+                    }
+                },
+                ThenProj::Fut2(fut) => {
+                    let output = ready!(fut.poll(cx));
+                    this.inner.set(ThenInner::Done);
+                    return Poll::Ready(output);
+                }
+                ThenProj::Done => panic!("TryFlatten::poll called after completion"),
+                ThenProj::Canceled => panic!("TryFlatten::poll called after cancel"),
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        let inner = this.inner.as_mut().project();
+        match inner {
+            ThenProj::Fut1(outer) => ready!(outer.poll_cancel(cx)),
+            // the outer future has already completed
+            ThenProj::Fut2(fut) => ready!(fut.poll_cancel(cx)),
+            ThenProj::Done | ThenProj::Canceled => {}
+        }
+
+        this.inner.set(ThenInner::Canceled);
+        Poll::Ready(())
+    }
+}
+
+/// `Abortable`被`AbortHandle::abort`中止时返回的错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Aborted;
+
+impl fmt::Display for Aborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("`Abortable` future has been aborted")
+    }
+}
+
+impl Error for Aborted {}
+
+struct AbortInner {
+    aborted: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+/// 从外部中止`Abortable`的句柄
+#[derive(Clone)]
+pub struct AbortHandle {
+    inner: Arc<AbortInner>,
+}
+
+impl AbortHandle {
+    /// 请求中止。之后`Abortable`会取消内部的future，完成后返回`Err(Aborted)`。
+    /// 内部的future已经完成时没有效果
+    pub fn abort(&self) {
+        self.inner.aborted.store(true, Ordering::Release);
+        if let Some(waker) = self.inner.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.inner.aborted.load(Ordering::Acquire)
+    }
+}
+
+/// 返回一个可以通过`AbortHandle`从外部中止的future
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use std::task::Poll;
+/// use abort_safe_future::{abortable, pending, ready};
+/// use abort_safe_future::combinator::Aborted;
+/// use abort_safe_future::testing::{checked, poll_cancel_once, poll_once};
+///
+/// let (inner, finalized) = checked(pending::<u32>());
+/// let (fut, handle) = abortable(inner);
+/// let mut fut = Box::pin(ManuallyDrop::new(fut));
+/// assert!(poll_once(fut.as_mut()).is_pending());
+/// handle.abort();
+/// assert_eq!(poll_once(fut.as_mut()), Poll::Ready(Err(Aborted)));
+/// assert!(finalized.is_finalized());
+///
+/// // aborting after the inner future has finished changes nothing
+/// let (fut, handle) = abortable(ready(1));
+/// let mut fut = Box::pin(ManuallyDrop::new(fut));
+/// assert_eq!(poll_once(fut.as_mut()), Poll::Ready(Ok(1)));
+/// handle.abort();
+/// assert!(poll_cancel_once(fut.as_mut()).is_ready());
+/// ```
+pub fn abortable<Fut: AbortSafeFuture>(fut: Fut) -> (Abortable<Fut>, AbortHandle) {
+    let inner = Arc::new(AbortInner {
+        aborted: AtomicBool::new(false),
+        waker: Mutex::new(None),
+    });
+    let handle = AbortHandle { inner: inner.clone() };
+    (Abortable::new(fut, inner), handle)
+}
+
+#[pin_project]
+pub struct Abortable<Fut> {
+    #[pin]
+    fut: Option<ManuallyDrop<Fut>>,
+    inner: Option<Arc<AbortInner>>,
+}
+
+impl<Fut> Abortable<Fut> {
+    fn new(fut: Fut, inner: Arc<AbortInner>) -> Self {
+        Self {
+            fut: Some(ManuallyDrop::new(fut)),
+            inner: Some(inner),
+        }
+    }
+}
+
+impl<Fut: AbortSafeFuture> AbortSafeFuture for Abortable<Fut> {
+    type Output = Result<Fut::Output, Aborted>;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        let inner = match this.inner {
+            Some(inner) => inner,
+            None => panic!("Abortable::poll called after completion or after cancel"),
+        };
+        let fut = this.fut.as_mut().as_pin_mut().unwrap();
+
+        if inner.aborted.load(Ordering::Acquire) {
+            ready!(fut.poll_cancel(cx));
+            this.fut.set(None);
+            // release the shared state
+            *this.inner = None;
+            return Poll::Ready(Err(Aborted));
+        }
+
+        // register before polling, so an abort during `poll` is not missed
+        *inner.waker.lock().unwrap() = Some(cx.waker().clone());
+        let output = ready!(fut.poll(cx));
+        this.fut.set(None);
+        *this.inner = None;
+        Poll::Ready(Ok(output))
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+            ready!(fut.poll_cancel(cx));
+            this.fut.set(None);
+        }
+
+        *this.inner = None;
+        Poll::Ready(())
+    }
+}
+
+struct ScopeState<'a> {
+    spawned: Vec<ManuallyDrop<BoxAbortSafeFuture<'a, ()>>>,
+    closed: bool,
+}
+
+/// `scope`中用来`spawn`子future的句柄
+#[derive(Clone)]
+pub struct ScopeHandle<'a> {
+    state: Rc<RefCell<ScopeState<'a>>>,
+}
+
+impl<'a> ScopeHandle<'a> {
+    /// 添加一个子future，它和主future一起执行。`scope`结束之后不能再调用
+    pub fn spawn(&self, fut: impl AbortSafeFuture<Output = ()> + 'a) {
+        let mut state = self.state.borrow_mut();
+        if state.closed {
+            panic!("ScopeHandle::spawn called after the scope has finished");
+        }
+        state.spawned.push(ManuallyDrop::new(fut.boxed()));
+    }
+}
+
+/// 结构化并发：主future和它`spawn`的子future一起执行。
+///
+/// 主future完成时，还没有完成的子future会被取消，全部取消完成之后才返回主future的结果。
+/// 取消`Scope`时，主future和所有的子future都会被取消。
+///
+/// ```
+/// use abort_safe_future::{pending, ready};
+/// use abort_safe_future::combinator::scope;
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::testing::DropOrderRecorder;
+///
+/// let recorder = DropOrderRecorder::new();
+/// let out = block_on(scope(|handle| {
+///     handle.spawn(recorder.wrap("child", pending::<()>()));
+///     recorder.wrap("main", ready(42))
+/// }));
+/// assert_eq!(out, 42);
+/// // the child never finished, it was cancelled before `scope` returned
+/// assert_eq!(recorder.events(), ["main", "child"]);
+/// ```
+pub fn scope<'a, F, Fut>(f: F) -> Scope<'a, Fut>
+where
+    F: FnOnce(ScopeHandle<'a>) -> Fut,
+    Fut: AbortSafeFuture,
+{
+    let handle = ScopeHandle {
+        state: Rc::new(RefCell::new(ScopeState {
+            spawned: Vec::new(),
+            closed: false,
+        })),
+    };
+    let main = f(handle.clone());
+    Scope::new(main, handle)
+}
+
+#[pin_project(project = ScopeProj)]
+pub struct Scope<'a, Fut: AbortSafeFuture> {
+    #[pin]
+    main: Option<ManuallyDrop<Fut>>,
+    output: Option<Fut::Output>,
+    children: Vec<ManuallyDrop<BoxAbortSafeFuture<'a, ()>>>,
+    handle: Option<ScopeHandle<'a>>,
+}
+
+impl<'a, Fut: AbortSafeFuture> Scope<'a, Fut> {
+    fn new(main: Fut, handle: ScopeHandle<'a>) -> Self {
+        Self {
+            main: Some(ManuallyDrop::new(main)),
+            output: None,
+            children: Vec::new(),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl<'a, Fut: AbortSafeFuture> ScopeProj<'_, 'a, Fut> {
+    /// move the futures spawned since the last call into `children`
+    fn collect_spawned(&mut self) {
+        if let Some(handle) = self.handle {
+            self.children.append(&mut handle.state.borrow_mut().spawned);
+        }
+    }
+
+    /// polls or cancels every child, finished children are removed
+    fn drive_children(&mut self, cx: &mut Context<'_>, cancel: bool) {
+        let mut i = 0;
+        while i < self.children.len() {
+            let child = Pin::new(&mut self.children[i]);
+            let ready = if cancel {
+                child.poll_cancel(cx).is_ready()
+            } else {
+                child.poll(cx).is_ready()
+            };
+
+            if ready {
+                // the boxed child has already been freed
+                self.children.swap_remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    fn has_spawned(&self) -> bool {
+        match &self.handle {
+            Some(handle) => !handle.state.borrow().spawned.is_empty(),
+            None => false,
+        }
+    }
+
+    /// closes the handle once nothing is left, returns whether everything has finished
+    fn finish(&mut self, cx: &mut Context<'_>) -> bool {
+        if self.has_spawned() {
+            // children spawned by other children have not been driven yet
+            cx.waker().wake_by_ref();
+            return false;
+        }
+
+        if self.main.is_some() || !self.children.is_empty() {
+            return false;
+        }
+
+        if let Some(handle) = self.handle.take() {
+            handle.state.borrow_mut().closed = true;
+        }
+        // release the buffer
+        *self.children = Vec::new();
+        true
+    }
+}
+
+impl<'a, Fut: AbortSafeFuture> AbortSafeFuture for Scope<'a, Fut> {
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if this.handle.is_none() {
+            panic!("Scope::poll called after completion or after cancel")
+        }
+
+        this.collect_spawned();
+        if let Some(main) = this.main.as_mut().as_pin_mut() {
+            if let Poll::Ready(output) = main.poll(cx) {
+                *this.output = Some(output);
+                this.main.set(None);
+            }
+        }
+        this.collect_spawned();
+
+        // the main future has finished, the remaining children are cancelled
+        let main_done = this.output.is_some();
+        this.drive_children(cx, main_done);
+
+        if this.finish(cx) {
+            return Poll::Ready(this.output.take().unwrap());
+        }
+
+        Poll::Pending
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if this.handle.is_none() {
+            return Poll::Ready(());
+        }
+
+        this.collect_spawned();
+        if let Some(main) = this.main.as_mut().as_pin_mut() {
+            if main.poll_cancel(cx).is_ready() {
+                this.main.set(None);
+            }
+        }
+
+        this.drive_children(cx, true);
+
+        if !this.finish(cx) {
+            return Poll::Pending;
+        }
+
+        // drop output
+        *this.output = None;
+        Poll::Ready(())
+    }
+}
+
+/// 协作式的取消信号。
+///
+/// `with_cancel_token`在`poll`内部的future时会把它设置为当前的token，内部的叶子future可以通过
+/// `current_cancel_token`检查是否被请求取消，主动提前结束。它不会取消任何future，
+/// 真正的取消仍然通过`poll_cancel`进行。
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<CancelTokenInner>,
+}
+
+#[derive(Debug, Default)]
+struct CancelTokenInner {
+    canceled: AtomicBool,
+    // woken by `cancel`, e.g. the thread blocked in `executor::block_on_cancelable`
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 设置取消标志，并唤醒所有等待取消的waker
+    pub fn cancel(&self) {
+        self.inner.canceled.store(true, Ordering::Release);
+        let wakers = mem::take(&mut *self.inner.wakers.lock().unwrap());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    pub fn is_canceled(&self) -> bool {
+        self.inner.canceled.load(Ordering::Acquire)
+    }
+
+    /// `cancel`时唤醒`waker`，已经被取消的话立即唤醒
+    pub(crate) fn register(&self, waker: &Waker) {
+        let mut wakers = self.inner.wakers.lock().unwrap();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+        drop(wakers);
+
+        // `cancel` may have taken the wakers before we pushed ours
+        if self.is_canceled() {
+            waker.wake_by_ref();
+        }
+    }
+
+    pub(crate) fn unregister(&self, waker: &Waker) {
+        self.inner.wakers.lock().unwrap().retain(|w| !w.will_wake(waker));
+    }
+}
+
+/// 叶子future因为`CancelToken`提前结束时可以返回的错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Canceled;
+
+impl fmt::Display for Canceled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("cancellation has been requested")
+    }
+}
+
+impl Error for Canceled {}
+
+thread_local! {
+    static CURRENT_TOKEN: RefCell<Option<CancelToken>> = const { RefCell::new(None) };
+}
+
+/// 当前正在被`poll`的最内层`with_cancel_token`的token。不在`with_cancel_token`内部时返回`None`
+pub fn current_cancel_token() -> Option<CancelToken> {
+    CURRENT_TOKEN.with(|current| current.borrow().clone())
+}
+
+/// 恢复外层的token，`poll`panic时也一样
+struct RestoreToken(Option<CancelToken>);
+
+impl Drop for RestoreToken {
+    fn drop(&mut self) {
+        let prev = self.0.take();
+        CURRENT_TOKEN.with(|current| *current.borrow_mut() = prev);
+    }
+}
+
+fn enter_token(token: &CancelToken) -> RestoreToken {
+    let prev = CURRENT_TOKEN.with(|current| current.borrow_mut().replace(token.clone()));
+    RestoreToken(prev)
+}
+
+/// 在`poll`和`poll_cancel` `fut`的过程中，把`token`设置为当前的token
+///
+/// ```
+/// use std::task::Poll;
+/// use abort_safe_future::poll_fn;
+/// use abort_safe_future::combinator::{current_cancel_token, with_cancel_token, CancelToken, Canceled};
+/// use abort_safe_future::executor::block_on;
+///
+/// let leaf = poll_fn(|_cx| {
+///     match current_cancel_token() {
+///         Some(token) if token.is_canceled() => Poll::Ready(Err(Canceled)),
+///         _ => Poll::Ready(Ok(())),
+///     }
+/// });
+///
+/// let token = CancelToken::new();
+/// token.cancel();
+/// assert_eq!(block_on(with_cancel_token(token, leaf)), Err(Canceled));
+/// ```
+pub fn with_cancel_token<Fut: AbortSafeFuture>(token: CancelToken, fut: Fut) -> WithCancelToken<Fut> {
+    WithCancelToken::new(token, fut)
+}
+
+#[pin_project]
+pub struct WithCancelToken<Fut> {
+    #[pin]
+    fut: Option<ManuallyDrop<Fut>>,
+    token: Option<CancelToken>,
+}
+
+impl<Fut> WithCancelToken<Fut> {
+    pub fn new(token: CancelToken, fut: Fut) -> Self {
+        Self {
+            fut: Some(ManuallyDrop::new(fut)),
+            token: Some(token),
+        }
+    }
+}
+
+impl<Fut: AbortSafeFuture> AbortSafeFuture for WithCancelToken<Fut> {
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        let (fut, token) = match (this.fut.as_mut().as_pin_mut(), this.token.as_ref()) {
+            (Some(fut), Some(token)) => (fut, token),
+            _ => panic!("WithCancelToken::poll called after completion or after cancel"),
+        };
+
+        let restore = enter_token(token);
+        let output = fut.poll(cx);
+        drop(restore);
+
+        let output = ready!(output);
+        this.fut.set(None);
+        // release the token
+        *this.token = None;
+        Poll::Ready(output)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let (Some(fut), Some(token)) = (this.fut.as_mut().as_pin_mut(), this.token.as_ref()) {
+            let restore = enter_token(token);
+            let canceled = fut.poll_cancel(cx);
+            drop(restore);
+            ready!(canceled);
+            this.fut.set(None);
+        }
+
+        // release the token
+        *this.token = None;
+        Poll::Ready(())
+    }
+}
@@ -0,0 +1,87 @@
+//! 把abort safe future当作trait object使用。
+//!
+//! `AbortSafeFuture`是object safe的：`poll`和`poll_cancel`的接收者都是`Pin<&mut ManuallyDrop<Self>>`，
+//! 对`dyn AbortSafeFuture`来说就是`Pin<&mut ManuallyDrop<dyn AbortSafeFuture<Output = T>>>`，
+//! 需要`arbitrary_self_types`。所以trait object必须放在`Pin<Box<ManuallyDrop<_>>>`里，
+//! 再通过`Pin::as_mut`得到接收者，调用会动态分发到具体类型的`poll`/`poll_cancel`。
+//!
+//! `DynAbortSafe`本身没有实现`AbortSafeFuture`，因为它被`ManuallyDrop`包住之后没有人会释放`Box`。
+//! 需要把它交给组合子时，用`Some`转换成`BoxAbortSafeFuture`，完成或者取消之后会释放`Box`。
+//!
+//! ```
+//! #![feature(arbitrary_self_types)]
+//! use std::task::{Context, Poll};
+//! use abort_safe_future::{pending, ready, AbortSafeFutureExt};
+//! use abort_safe_future::combinator::Compat;
+//! use abort_safe_future::dyn_support::{drive, into_dyn, poll_cancel_dyn, poll_dyn, DynAbortSafe};
+//! use abort_safe_future::testing::noop_waker;
+//!
+//! let futs: Vec<DynAbortSafe<'_, u32>> = vec![
+//!     into_dyn(Compat::new(async { 1 })),
+//!     into_dyn(ready(1).then(|x| ready(x + 1))),
+//!     into_dyn(ready(2).map(|x| x + 1)),
+//! ];
+//!
+//! let outputs: Vec<u32> = futs.into_iter().map(drive).collect();
+//! assert_eq!(outputs, [1, 2, 3]);
+//!
+//! // `poll_cancel` is dispatched to the concrete type as well
+//! let waker = noop_waker();
+//! let mut cx = Context::from_waker(&waker);
+//! let mut fut: DynAbortSafe<'_, u32> = into_dyn(pending::<u32>().map(|x| x + 1));
+//! assert!(poll_dyn(&mut fut, &mut cx).is_pending());
+//! assert_eq!(poll_cancel_dyn(&mut fut, &mut cx), Poll::Ready(()));
+//! ```
+
+use std::mem::ManuallyDrop;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use crate::executor::block_on_boxed;
+use crate::future::{AbortSafeFuture, BoxAbortSafeFuture};
+
+/// 类型擦除的abort safe future
+pub type DynAbortSafe<'a, T> = Pin<Box<ManuallyDrop<dyn AbortSafeFuture<Output = T> + 'a>>>;
+
+/// 可以在线程之间移动的`DynAbortSafe`
+pub type DynAbortSafeSend<'a, T> = Pin<Box<ManuallyDrop<dyn AbortSafeFuture<Output = T> + Send + 'a>>>;
+
+/// 把`fut`装箱并擦除类型
+pub fn into_dyn<'a, F>(fut: F) -> DynAbortSafe<'a, F::Output>
+where
+    F: AbortSafeFuture + 'a,
+{
+    Box::pin(ManuallyDrop::new(fut))
+}
+
+/// 把`fut`装箱并擦除类型，保留`Send`
+pub fn into_dyn_send<'a, F>(fut: F) -> DynAbortSafeSend<'a, F::Output>
+where
+    F: AbortSafeFuture + Send + 'a,
+{
+    Box::pin(ManuallyDrop::new(fut))
+}
+
+/// 去掉`Send`约束
+pub fn upcast<'a, T>(fut: DynAbortSafeSend<'a, T>) -> DynAbortSafe<'a, T> {
+    fut
+}
+
+/// 转换成会在完成或者取消之后释放`Box`的`BoxAbortSafeFuture`
+pub fn into_boxed<T>(fut: DynAbortSafe<'_, T>) -> BoxAbortSafeFuture<'_, T> {
+    Some(fut)
+}
+
+/// 对trait object调用`poll`
+pub fn poll_dyn<T>(fut: &mut DynAbortSafe<'_, T>, cx: &mut Context<'_>) -> Poll<T> {
+    fut.as_mut().poll(cx)
+}
+
+/// 对trait object调用`poll_cancel`
+pub fn poll_cancel_dyn<T>(fut: &mut DynAbortSafe<'_, T>, cx: &mut Context<'_>) -> Poll<()> {
+    fut.as_mut().poll_cancel(cx)
+}
+
+/// 在当前线程上执行trait object直到完成，见`executor::block_on_boxed`
+pub fn drive<T>(fut: DynAbortSafe<'_, T>) -> T {
+    block_on_boxed(fut)
+}
@@ -1,10 +1,19 @@
+use std::hint;
 use std::mem::ManuallyDrop;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::pin::Pin;
-use std::sync::Arc;
-use std::task::{Context, Poll, Wake};
+use std::rc::Rc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, Wake, Waker};
 use std::thread;
 use std::thread::Thread;
-use crate::future::AbortSafeFuture;
+use std::time::{Duration, Instant};
+use crate::combinator::{with_cancel_token, CancelToken};
+use crate::future::{AbortSafeFuture, AbortSafeFutureExt};
+use crate::helpers::pin_manually_drop_as_mut;
+use crate::time::Timer;
 
 struct ThreadWaker(Thread);
 
@@ -14,17 +23,738 @@ impl Wake for ThreadWaker {
     }
 }
 
+/// 在当前线程上执行`fut`直到完成。`fut`被固定在栈上，不需要分配内存。
+///
+/// ```
+/// use abort_safe_future::combinator::Compat;
+/// use abort_safe_future::executor::block_on;
+///
+/// let fut = Compat::new(async { 1 + 2 });
+/// assert_eq!(block_on(fut), 3);
+/// ```
+///
+/// `poll`返回`Poll::Ready`时`fut`已经回收了自己的资源，`block_on`不会再调用它的`poll_cancel`。
+/// 持有`AsyncDrop`值的future要在完成之前驱动它的`poll_drop`：
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::cell::Cell;
+/// use std::mem::ManuallyDrop;
+/// use std::pin::Pin;
+/// use std::task::{Context, Poll};
+/// use abort_safe_future::{AbortSafeFuture, AsyncDrop, SyncDrop};
+/// use abort_safe_future::executor::block_on;
+///
+/// struct TestDrop<'a>(&'a Cell<u32>);
+///
+/// impl Drop for TestDrop<'_> {
+///     fn drop(&mut self) {
+///         self.0.set(self.0.get() + 1);
+///     }
+/// }
+///
+/// struct Holder<'a>(ManuallyDrop<SyncDrop<TestDrop<'a>>>);
+///
+/// impl AbortSafeFuture for Holder<'_> {
+///     type Output = ();
+///
+///     fn poll(self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+///         // release the value before reporting completion
+///         self.poll_cancel(cx)
+///     }
+///
+///     fn poll_cancel(self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+///         Pin::new(&mut Pin::into_inner(self).0).poll_drop(cx)
+///     }
+/// }
+///
+/// let drops = Cell::new(0);
+/// block_on(Holder(ManuallyDrop::new(SyncDrop::new(TestDrop(&drops)))));
+/// assert_eq!(drops.get(), 1);
+/// ```
+///
+/// `fut`的`poll`panic时，会先把它的`poll_cancel`驱动到完成，再继续unwind。
+///
+/// ```
+/// use std::cell::Cell;
+/// use std::panic::{catch_unwind, AssertUnwindSafe};
+/// use std::task::Poll;
+/// use abort_safe_future::{join, pending, poll_fn, AbortSafeFutureExt};
+/// use abort_safe_future::executor::block_on;
+///
+/// let guard_ran = Cell::new(false);
+/// let result = catch_unwind(AssertUnwindSafe(|| {
+///     let guard = pending::<()>().inspect_cancel(|| guard_ran.set(true));
+///     let boom = poll_fn(|_| -> Poll<()> { panic!("boom") });
+///     block_on(join(guard, boom))
+/// }));
+/// assert!(result.is_err());
+/// assert!(guard_ran.get());
+/// ```
 pub fn block_on<T>(fut: impl AbortSafeFuture<Output = T>) -> T {
-    let mut fut = Box::pin(ManuallyDrop::new(fut)) as Pin<Box<ManuallyDrop<dyn AbortSafeFuture<Output = T>>>>;
+    let mut fut = ManuallyDrop::new(fut);
+    // `fut` is shadowed, so it can never be moved again
+    let fut = unsafe { Pin::new_unchecked(&mut fut) };
+    block_on_pinned(fut)
+}
+
+/// 与`block_on`相同，用于已经类型擦除的future，完成之后会释放`Box`
+pub fn block_on_boxed<T>(mut fut: Pin<Box<ManuallyDrop<dyn AbortSafeFuture<Output = T> + '_>>>) -> T {
+    block_on_pinned(fut.as_mut())
+}
+
+/// `poll`panic时，在unwind的过程中取消future，避免它持有的资源泄漏。
+/// 取消的过程中再次panic会导致进程abort
+struct CancelOnPanic<'a, F: AbortSafeFuture + ?Sized>(Pin<&'a mut ManuallyDrop<F>>);
+
+impl<F: AbortSafeFuture + ?Sized> Drop for CancelOnPanic<'_, F> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            block_on_cancel(self.0.as_mut());
+        }
+    }
+}
+
+fn block_on_pinned<F: AbortSafeFuture + ?Sized>(fut: Pin<&mut ManuallyDrop<F>>) -> F::Output {
+    block_on_pinned_with(fut, &mut Park)
+}
+
+/// `block_on_with`创建的waker。被唤醒时先设置标志，再`unpark`执行`block_on_with`的线程，
+/// 所以无论`ParkStrategy`是自旋还是`park`都不会错过唤醒
+pub struct WakeSignal {
+    woken: AtomicBool,
+    thread: Thread,
+}
+
+impl WakeSignal {
+    /// 从上次调用以来是否被唤醒过，同时清除标志
+    pub fn take(&self) -> bool {
+        self.woken.swap(false, Ordering::AcqRel)
+    }
+}
+
+impl Wake for WakeSignal {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+        self.thread.unpark();
+    }
+}
+
+/// `block_on_with`在future返回`Poll::Pending`之后等待唤醒的方式
+pub trait ParkStrategy {
+    /// 等待直到`signal`被唤醒，调用时在执行`block_on_with`的线程上
+    fn wait(&mut self, signal: &WakeSignal);
+}
+
+/// 调用`thread::park`，与`block_on`相同
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Park;
+
+impl ParkStrategy for Park {
+    fn wait(&mut self, signal: &WakeSignal) {
+        while !signal.take() {
+            thread::park();
+        }
+    }
+}
+
+/// 一直自旋，适用于很快就会被唤醒的future
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Spin;
+
+impl ParkStrategy for Spin {
+    fn wait(&mut self, signal: &WakeSignal) {
+        while !signal.take() {
+            hint::spin_loop();
+        }
+    }
+}
+
+/// 先自旋最多`spins`次，还没有被唤醒的话再`park`
+#[derive(Debug, Clone, Copy)]
+pub struct SpinThenPark {
+    pub spins: u32,
+}
+
+impl ParkStrategy for SpinThenPark {
+    fn wait(&mut self, signal: &WakeSignal) {
+        for _ in 0..self.spins {
+            if signal.take() {
+                return;
+            }
+            hint::spin_loop();
+        }
+        Park.wait(signal)
+    }
+}
+
+/// 与`block_on`相同，但是由`strategy`决定`Pending`时如何等待
+///
+/// ```
+/// use std::task::Poll;
+/// use abort_safe_future::poll_fn;
+/// use abort_safe_future::executor::{block_on_with, Park, Spin, SpinThenPark};
+///
+/// let make = || {
+///     let mut rounds = 3;
+///     poll_fn(move |cx| {
+///         if rounds == 0 {
+///             return Poll::Ready("done");
+///         }
+///         rounds -= 1;
+///         cx.waker().wake_by_ref();
+///         Poll::Pending
+///     })
+/// };
+///
+/// assert_eq!(block_on_with(make(), Park), "done");
+/// assert_eq!(block_on_with(make(), Spin), "done");
+/// assert_eq!(block_on_with(make(), SpinThenPark { spins: 100 }), "done");
+/// ```
+pub fn block_on_with<T, S: ParkStrategy>(fut: impl AbortSafeFuture<Output = T>, mut strategy: S) -> T {
+    let mut fut = ManuallyDrop::new(fut);
+    // `fut` is shadowed, so it can never be moved again
+    let fut = unsafe { Pin::new_unchecked(&mut fut) };
+    block_on_pinned_with(fut, &mut strategy)
+}
+
+fn block_on_pinned_with<F, S>(fut: Pin<&mut ManuallyDrop<F>>, strategy: &mut S) -> F::Output
+where
+    F: AbortSafeFuture + ?Sized,
+    S: ParkStrategy,
+{
+    let signal = Arc::new(WakeSignal {
+        woken: AtomicBool::new(false),
+        thread: thread::current(),
+    });
+    let waker = Waker::from(signal.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let mut fut = CancelOnPanic(fut);
+    loop {
+        match fut.0.as_mut().poll(&mut cx) {
+            Poll::Ready(res) => return res,
+            Poll::Pending => strategy.wait(&signal),
+        }
+    }
+}
+
+/// 与`block_on`类似，但是每次`poll`之前都会检查`cancel`。`fut`被固定在栈上。
+/// 如果`cancel`被取消了，就不再`poll`，而是调用`poll_cancel`直到完成，然后返回`None`。
+///
+/// `CancelToken::cancel`会唤醒当前线程，所以在其他线程上取消也能立即生效。
+/// `fut`运行在`with_cancel_token`里，叶子future可以通过`combinator::current_cancel_token`读到`cancel`。
+///
+/// ```
+/// use std::cell::Cell;
+/// use std::thread;
+/// use std::time::Duration;
+/// use abort_safe_future::{pending, AbortSafeFutureExt};
+/// use abort_safe_future::combinator::CancelToken;
+/// use abort_safe_future::executor::block_on_cancelable;
+///
+/// let cancel = CancelToken::new();
+/// let canceller = {
+///     let cancel = cancel.clone();
+///     thread::spawn(move || {
+///         thread::sleep(Duration::from_millis(20));
+///         cancel.cancel();
+///     })
+/// };
+///
+/// let cleaned_up = Cell::new(false);
+/// let fut = pending::<u32>().inspect_cancel(|| cleaned_up.set(true));
+/// assert_eq!(block_on_cancelable(fut, cancel), None);
+/// assert!(cleaned_up.get());
+/// canceller.join().unwrap();
+/// ```
+pub fn block_on_cancelable<T>(fut: impl AbortSafeFuture<Output = T>, cancel: CancelToken) -> Option<T> {
+    let mut fut = ManuallyDrop::new(with_cancel_token(cancel.clone(), fut));
+    // `fut` is shadowed, so it can never be moved again
+    let mut fut = unsafe { Pin::new_unchecked(&mut fut) };
 
     let t = thread::current();
     let waker = Arc::new(ThreadWaker(t)).into();
     let mut cx = Context::from_waker(&waker);
+    cancel.register(&waker);
+
+    let output = loop {
+        if cancel.is_canceled() {
+            drive_cancel(fut.as_mut(), &mut cx);
+            break None;
+        }
 
-    loop {
         match fut.as_mut().poll(&mut cx) {
-            Poll::Ready(res) => return res,
+            Poll::Ready(res) => break Some(res),
             Poll::Pending => thread::park(),
         }
+    };
+
+    cancel.unregister(&waker);
+    output
+}
+
+/// 在当前线程上阻塞地取消`fut`
+pub(crate) fn block_on_cancel<F: AbortSafeFuture + ?Sized>(fut: Pin<&mut ManuallyDrop<F>>) {
+    let t = thread::current();
+    let waker = Arc::new(ThreadWaker(t)).into();
+    let mut cx = Context::from_waker(&waker);
+
+    drive_cancel(fut, &mut cx);
+}
+
+/// 在当前线程上把`poll_cancel`驱动到`Poll::Ready`
+fn drive_cancel<F: AbortSafeFuture + ?Sized>(mut fut: Pin<&mut ManuallyDrop<F>>, cx: &mut Context<'_>) {
+    while fut.as_mut().poll_cancel(cx).is_pending() {
+        thread::park();
+    }
+}
+
+/// 在当前线程上轮流执行所有的future，按照原来的顺序返回结果。每个future完成之后都会被回收
+///
+/// ```
+/// use std::task::Poll;
+/// use abort_safe_future::{poll_fn, AbortSafeFuture};
+/// use abort_safe_future::executor::block_on_all;
+/// use abort_safe_future::testing::checked;
+///
+/// // ready after `n` polls
+/// fn steps(n: u32) -> impl AbortSafeFuture<Output = u32> {
+///     let mut polls = 0;
+///     poll_fn(move |cx| {
+///         polls += 1;
+///         if polls < n {
+///             cx.waker().wake_by_ref();
+///             return Poll::Pending;
+///         }
+///         Poll::Ready(n)
+///     })
+/// }
+///
+/// let (futs, finalized): (Vec<_>, Vec<_>) = [3, 1, 2].iter().map(|&n| checked(steps(n))).unzip();
+/// assert_eq!(block_on_all(futs), [3, 1, 2]);
+/// assert!(finalized.iter().all(|f| f.is_finalized()));
+/// ```
+pub fn block_on_all<T>(futs: Vec<impl AbortSafeFuture<Output = T>>) -> Vec<T> {
+    let mut futs: Vec<_> = futs
+        .into_iter()
+        .map(|fut| Some(Box::pin(ManuallyDrop::new(fut))))
+        .collect();
+    let mut outputs: Vec<Option<T>> = futs.iter().map(|_| None).collect();
+
+    let t = thread::current();
+    let waker = Arc::new(ThreadWaker(t)).into();
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        let mut progress = false;
+        for (slot, output) in futs.iter_mut().zip(outputs.iter_mut()) {
+            if let Some(fut) = slot {
+                if let Poll::Ready(res) = fut.as_mut().poll(&mut cx) {
+                    *output = Some(res);
+                    *slot = None;
+                    progress = true;
+                }
+            }
+        }
+
+        if futs.iter().all(Option::is_none) {
+            return outputs.into_iter().map(Option::unwrap).collect();
+        }
+
+        if !progress {
+            thread::park();
+        }
+    }
+}
+
+struct TaskWaker {
+    woken: AtomicBool,
+    thread: Thread,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+        self.thread.unpark();
+    }
+}
+
+struct Task<'a> {
+    fut: Pin<Box<ManuallyDrop<dyn AbortSafeFuture<Output = ()> + 'a>>>,
+    waker: Arc<TaskWaker>,
+    finished: bool,
+}
+
+struct JoinState<T> {
+    output: Option<T>,
+    waker: Option<Waker>,
+}
+
+/// 单线程的执行器，`run`会在当前线程上轮流执行所有`spawn`的任务。
+///
+/// 析构时还没有完成的任务会被取消，直到它们的`poll_cancel`返回`Poll::Ready`。
+///
+/// ```
+/// use abort_safe_future::AbortSafeStreamExt;
+/// use abort_safe_future::combinator::Compat;
+/// use abort_safe_future::executor::{block_on, LocalExecutor};
+///
+/// let (tx, rx) = futures::channel::mpsc::unbounded();
+/// let mut executor = LocalExecutor::new();
+/// let received = executor.spawn(abort_safe_future::stream::Compat::new(rx).collect::<Vec<u32>>());
+/// executor.spawn(Compat::new(async move {
+///     for i in 1..=3 {
+///         tx.unbounded_send(i).unwrap();
+///     }
+/// }));
+/// executor.run();
+/// assert_eq!(block_on(received), [1, 2, 3]);
+/// ```
+///
+/// ```
+/// use std::cell::Cell;
+/// use abort_safe_future::{pending, AbortSafeFutureExt};
+/// use abort_safe_future::executor::LocalExecutor;
+///
+/// let cancelled = Cell::new(false);
+/// let mut executor = LocalExecutor::new();
+/// executor.spawn(pending::<()>().inspect_cancel(|| cancelled.set(true)));
+/// drop(executor);
+/// assert!(cancelled.get());
+/// ```
+pub struct LocalExecutor<'a> {
+    tasks: Vec<Task<'a>>,
+}
+
+impl<'a> LocalExecutor<'a> {
+    pub fn new() -> Self {
+        Self { tasks: Vec::new() }
+    }
+
+    /// 添加一个任务，返回的`JoinHandle`在任务完成时得到它的结果。
+    /// 任务只在`run`的时候执行
+    pub fn spawn<T: 'a>(&mut self, fut: impl AbortSafeFuture<Output = T> + 'a) -> JoinHandle<T> {
+        let state = Rc::new(RefCell::new(JoinState { output: None, waker: None }));
+        let task_state = state.clone();
+        let fut = fut.map(move |output| {
+            let mut state = task_state.borrow_mut();
+            state.output = Some(output);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        });
+
+        self.tasks.push(Task {
+            fut: Box::pin(ManuallyDrop::new(fut)),
+            waker: Arc::new(TaskWaker {
+                woken: AtomicBool::new(true),
+                thread: thread::current(),
+            }),
+            finished: false,
+        });
+
+        JoinHandle { state: Some(state) }
+    }
+
+    /// 执行所有任务直到它们全部完成，没有任务被唤醒时阻塞当前线程
+    pub fn run(&mut self) {
+        while !self.tasks.is_empty() {
+            let mut progress = false;
+            for task in self.tasks.iter_mut() {
+                if !task.waker.woken.swap(false, Ordering::AcqRel) {
+                    continue;
+                }
+                progress = true;
+
+                let waker = Waker::from(task.waker.clone());
+                let mut cx = Context::from_waker(&waker);
+                if task.fut.as_mut().poll(&mut cx).is_ready() {
+                    task.finished = true;
+                }
+            }
+
+            self.tasks.retain(|task| !task.finished);
+
+            if !progress {
+                thread::park();
+            }
+        }
+    }
+}
+
+impl<'a> Default for LocalExecutor<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Drop for LocalExecutor<'a> {
+    fn drop(&mut self) {
+        // every unfinished task must be driven through `poll_cancel`, otherwise its resources leak
+        for task in self.tasks.iter_mut() {
+            let waker = Waker::from(task.waker.clone());
+            let mut cx = Context::from_waker(&waker);
+            drive_cancel(task.fut.as_mut(), &mut cx);
+        }
+    }
+}
+
+/// `LocalExecutor::spawn`返回的句柄，完成时得到任务的结果。
+///
+/// 取消`JoinHandle`不会取消任务本身，任务仍然会在`run`中执行完。
+///
+/// ```
+/// use abort_safe_future::AbortSafeFutureExt;
+/// use abort_safe_future::combinator::Compat;
+/// use abort_safe_future::executor::{block_on, LocalExecutor};
+///
+/// let mut executor = LocalExecutor::new();
+/// let first = executor.spawn(Compat::new(async { 21 }));
+/// let second = executor.spawn(first.map(|x| x * 2));
+/// executor.run();
+/// assert!(second.is_finished());
+/// assert_eq!(block_on(second), 42);
+/// ```
+pub struct JoinHandle<T> {
+    state: Option<Rc<RefCell<JoinState<T>>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// 任务是否已经完成。结果已经被取走，或者句柄已经被取消时也返回`true`
+    pub fn is_finished(&self) -> bool {
+        match &self.state {
+            Some(state) => state.borrow().output.is_some(),
+            None => true,
+        }
+    }
+}
+
+impl<T> AbortSafeFuture for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = pin_manually_drop_as_mut(&mut self).get_mut();
+        let state = this.state.as_ref().expect("JoinHandle::poll called after completion or after cancel");
+
+        let output = state.borrow_mut().output.take();
+        match output {
+            Some(output) => {
+                // release the shared state
+                this.state = None;
+                Poll::Ready(output)
+            }
+            None => {
+                state.borrow_mut().waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
     }
-}
\ No newline at end of file
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).get_mut();
+        if let Some(state) = this.state.take() {
+            state.borrow_mut().waker = None;
+        }
+
+        Poll::Ready(())
+    }
+}
+
+struct TimerShared {
+    state: Mutex<TimerState>,
+    condvar: Condvar,
+}
+
+struct TimerState {
+    entries: BTreeMap<(Instant, u64), Waker>,
+    next_id: u64,
+    /// `ThreadTimer`s and `Sleep`s still alive, the background thread exits when it reaches zero
+    users: usize,
+}
+
+impl TimerShared {
+    fn release(&self, entry: Option<(Instant, u64)>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(key) = entry {
+            state.entries.remove(&key);
+        }
+        state.users -= 1;
+        drop(state);
+        self.condvar.notify_one();
+    }
+}
+
+/// 基于后台线程的`Timer`，所有的`sleep`共用一个线程
+///
+/// ```
+/// use std::time::{Duration, Instant};
+/// use abort_safe_future::executor::{block_on, ThreadTimer};
+/// use abort_safe_future::time::Timer;
+///
+/// let timer = ThreadTimer::new();
+/// let start = Instant::now();
+/// block_on(timer.sleep(Duration::from_millis(10)));
+/// assert!(start.elapsed() >= Duration::from_millis(10));
+/// ```
+pub struct ThreadTimer {
+    shared: Arc<TimerShared>,
+}
+
+impl ThreadTimer {
+    pub fn new() -> Self {
+        let shared = Arc::new(TimerShared {
+            state: Mutex::new(TimerState {
+                entries: BTreeMap::new(),
+                next_id: 0,
+                users: 1,
+            }),
+            condvar: Condvar::new(),
+        });
+
+        let background = shared.clone();
+        thread::spawn(move || run_timer(&background));
+        Self { shared }
+    }
+}
+
+fn run_timer(shared: &TimerShared) {
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        if state.users == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        while let Some(&key) = state.entries.keys().next() {
+            if key.0 > now {
+                break;
+            }
+            expired.push(state.entries.remove(&key).unwrap());
+        }
+
+        if !expired.is_empty() {
+            // wake without holding the lock
+            drop(state);
+            expired.into_iter().for_each(Waker::wake);
+            state = shared.state.lock().unwrap();
+            continue;
+        }
+
+        state = match state.entries.keys().next() {
+            Some(&(deadline, _)) => shared.condvar.wait_timeout(state, deadline - now).unwrap().0,
+            None => shared.condvar.wait(state).unwrap(),
+        };
+    }
+}
+
+impl Default for ThreadTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for ThreadTimer {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().users += 1;
+        Self { shared: self.shared.clone() }
+    }
+}
+
+impl Drop for ThreadTimer {
+    fn drop(&mut self) {
+        self.shared.release(None);
+    }
+}
+
+impl Timer for ThreadTimer {
+    type Sleep = Sleep;
+
+    fn sleep(&self, dur: Duration) -> Self::Sleep {
+        let mut state = self.shared.state.lock().unwrap();
+        state.users += 1;
+        let id = state.next_id;
+        state.next_id += 1;
+
+        Sleep {
+            shared: Some(self.shared.clone()),
+            deadline: Instant::now() + dur,
+            id,
+            registered: false,
+        }
+    }
+}
+
+/// `ThreadTimer::sleep`返回的future。取消时会注销计时，后台线程不会再唤醒它
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use std::sync::atomic::Ordering;
+/// use std::time::Duration;
+/// use abort_safe_future::executor::ThreadTimer;
+/// use abort_safe_future::testing::{counting_waker, poll_cancel_once, poll_once_with};
+/// use abort_safe_future::time::Timer;
+///
+/// let timer = ThreadTimer::new();
+/// let (waker, wakes) = counting_waker();
+/// let mut sleep = Box::pin(ManuallyDrop::new(timer.sleep(Duration::from_millis(10))));
+/// assert!(poll_once_with(sleep.as_mut(), &waker).is_pending());
+/// assert!(poll_cancel_once(sleep.as_mut()).is_ready());
+///
+/// // the entry is gone, so the deadline passes without a wake-up
+/// std::thread::sleep(Duration::from_millis(50));
+/// assert_eq!(wakes.load(Ordering::SeqCst), 0);
+/// ```
+pub struct Sleep {
+    shared: Option<Arc<TimerShared>>,
+    deadline: Instant,
+    id: u64,
+    registered: bool,
+}
+
+impl Sleep {
+    fn release(&mut self) {
+        if let Some(shared) = self.shared.take() {
+            let entry = if self.registered { Some((self.deadline, self.id)) } else { None };
+            shared.release(entry);
+        }
+    }
+}
+
+impl AbortSafeFuture for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = pin_manually_drop_as_mut(&mut self).get_mut();
+        let shared = match &this.shared {
+            Some(shared) => shared,
+            None => panic!("Sleep::poll called after completion or after cancel"),
+        };
+
+        if Instant::now() >= this.deadline {
+            this.release();
+            return Poll::Ready(());
+        }
+
+        shared.state.lock().unwrap().entries.insert((this.deadline, this.id), cx.waker().clone());
+        this.registered = true;
+        // the new deadline may be earlier than the one the background thread is waiting for
+        shared.condvar.notify_one();
+        Poll::Pending
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+        pin_manually_drop_as_mut(&mut self).get_mut().release();
+        Poll::Ready(())
+    }
+}
@@ -4,12 +4,20 @@ use core::pin::Pin;
 use core::mem::ManuallyDrop;
 
 use std::any::type_name;
+use std::future::Future;
+use std::hint;
+use std::ops::DerefMut;
 use std::task::ready;
-use crate::combinator::Then;
+use crate::combinator::{AndThen, ByRef, CatchUnwind, Compat, Flatten, Fuse, Guard, Inspect, InspectCancel, InspectErr, InspectOk, IntoStdFuture, Map, MapErr, MapOk, OrElse, Race, Shared, Then, TryFlatten, UnwrapOrElse};
 use crate::helpers::pin_manually_drop_as_mut;
+use crate::stream::{AbortSafeStream, FlattenStream, IntoStream};
 
 
 /// abort safe future
+///
+/// 只有future的所有者负责把它的`poll_cancel`驱动到`Poll::Ready`。
+/// 需要在不交出所有权的情况下把future交给组合子时，使用`AbortSafeFutureExt::by_ref`，
+/// 组合子取消这个借用时不会取消future本身。
 pub trait AbortSafeFuture {
     /// Future的结果类型
     type Output;
@@ -22,18 +30,56 @@ pub trait AbortSafeFuture {
 
     /// 当被取消时，调用此方法。
     /// 返回值为`Poll::Ready`时，表示取消成功，此时应该完成了一些资源的回收工作
+    ///
+    /// `poll`返回`Poll::Ready`时资源已经回收完了，不需要再调用此方法。
+    /// 即使调用了，也应该直接返回`Poll::Ready`，且没有副作用。
+    ///
+    /// 此方法返回`Poll::Ready`之后，之后的每次调用都必须直接返回`Poll::Ready`，且没有副作用，
+    /// 所以通用的驱动程序可以放心地重复调用。可以用`testing::assert_cancel_idempotent`检查。
     fn poll_cancel(self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()>;
 }
 
-impl<F: AbortSafeFuture + Unpin + ?Sized> AbortSafeFuture for &mut ManuallyDrop<F> {
+/// 可以转换成abort safe future的类型，用于`then`等组合子的闭包返回值。
+///
+/// abort safe future转换成它自己，std的`Future`（包括`async`块）用`Compat`包一层。
+/// 一个类型可能同时实现`Future`和`AbortSafeFuture`，所以两个实现用`M`区分，`M`由编译器推导。
+/// 同时实现了两者的类型无法推导出`M`，需要手动转换。
+///
+/// ```
+/// use abort_safe_future::{ready, AbortSafeFutureExt};
+/// use abort_safe_future::executor::block_on;
+///
+/// let fut = ready(1).then(|x| async move { x + 1 }).then(|x| ready(x * 10));
+/// assert_eq!(block_on(fut), 20);
+/// ```
+pub trait IntoAbortSafeFuture<M = AbortSafeMarker> {
+    type Output;
+    type Future: AbortSafeFuture<Output = Self::Output>;
+
+    fn into_abort_safe(self) -> Self::Future;
+}
+
+/// `IntoAbortSafeFuture`中表示abort safe future的标记
+pub struct AbortSafeMarker;
+
+/// `IntoAbortSafeFuture`中表示std `Future`的标记
+pub struct StdFutureMarker;
+
+impl<F: AbortSafeFuture> IntoAbortSafeFuture for F {
     type Output = F::Output;
+    type Future = F;
 
-    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        F::poll(Pin::new(&mut*self), cx)
+    fn into_abort_safe(self) -> Self::Future {
+        self
     }
+}
 
-    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
-        F::poll_cancel(Pin::new(&mut*self), cx)
+impl<F: Future> IntoAbortSafeFuture<StdFutureMarker> for F {
+    type Output = F::Output;
+    type Future = Compat<F>;
+
+    fn into_abort_safe(self) -> Self::Future {
+        Compat::new(self)
     }
 }
 
@@ -71,20 +117,6 @@ impl<F: AbortSafeFuture + Unpin + ?Sized> AbortSafeFuture for Option<Box<Manuall
     }
 }
 
-impl<F: AbortSafeFuture + ?Sized> AbortSafeFuture for Pin<&mut ManuallyDrop<F>> {
-    type Output = F::Output;
-
-    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let inner = pin_manually_drop_as_mut(&mut self).as_deref_mut();
-        F::poll(inner, cx)
-    }
-
-    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
-        let inner = pin_manually_drop_as_mut(&mut self).as_deref_mut();
-        F::poll_cancel(inner, cx)
-    }
-}
-
 impl<F: AbortSafeFuture + ?Sized> AbortSafeFuture for Option<Pin<Box<ManuallyDrop<F>>>> {
     type Output = F::Output;
 
@@ -120,16 +152,431 @@ impl<F: AbortSafeFuture + ?Sized> AbortSafeFuture for Option<Pin<Box<ManuallyDro
     }
 }
 
+/// 把`Pin<&mut ManuallyDrop<T>>`投影成`Pin<&mut T>`，用于在crate外实现组合子。
+///
+/// `ManuallyDrop<T>`不会移动`T`，所以这个投影是安全的。
+/// 但`ManuallyDrop`的作用是让`T`不会被析构，所以不应该通过返回值调用`Pin::set`，
+/// 否则旧的值会被直接析构，绕过了`poll_cancel`。
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use std::pin::Pin;
+/// use std::task::{Context, Poll};
+/// use abort_safe_future::{AbortSafeFuture, PinManuallyDropExt};
+///
+/// struct Twice<Fut>(ManuallyDrop<Fut>);
+///
+/// impl<Fut: AbortSafeFuture<Output = u32>> AbortSafeFuture for Twice<Fut> {
+///     type Output = u32;
+///
+///     fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<u32> {
+///         // `Twice` never moves its field
+///         let inner = unsafe { self.project_manually_drop().map_unchecked_mut(|this| &mut this.0) };
+///         inner.poll(cx).map(|x| x * 2)
+///     }
+///
+///     fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+///         let inner = unsafe { self.project_manually_drop().map_unchecked_mut(|this| &mut this.0) };
+///         inner.poll_cancel(cx)
+///     }
+/// }
+///
+/// let fut = Twice(ManuallyDrop::new(abort_safe_future::ready(21)));
+/// assert_eq!(abort_safe_future::executor::block_on(fut), 42);
+/// ```
+pub trait PinManuallyDropExt<T> {
+    fn project_manually_drop(&mut self) -> Pin<&mut T>;
+}
+
+impl<P, T> PinManuallyDropExt<T> for Pin<P>
+where
+    P: DerefMut<Target = ManuallyDrop<T>>,
+{
+    fn project_manually_drop(&mut self) -> Pin<&mut T> {
+        pin_manually_drop_as_mut(self)
+    }
+}
+
+/// 类型擦除的abort safe future。完成或者取消之后会释放`Box`
+///
+/// 没有直接为`Pin<Box<ManuallyDrop<F>>>`实现`AbortSafeFuture`，因为它自己也会被`ManuallyDrop`包住，
+/// 没有人会析构这个`Box`。已有的`Pin<Box<ManuallyDrop<F>>>`可以用`Some`转换成这个类型，
+/// 或者直接用`AbortSafeFutureExt::boxed`。
+///
+/// ```
+/// use std::task::Poll;
+/// use abort_safe_future::{poll_fn, ready, AbortSafeFutureExt, BoxAbortSafeFuture};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::testing::checked;
+///
+/// let (then, finalized) = checked(ready(1).then(|x| ready(x + 1)));
+/// let futs: Vec<BoxAbortSafeFuture<'_, u32>> = vec![
+///     ready(1).boxed(),
+///     then.boxed(),
+///     poll_fn(|_| Poll::Ready(3)).boxed(),
+/// ];
+/// let out: Vec<_> = futs.into_iter().map(block_on).collect();
+/// assert_eq!(out, [1, 2, 3]);
+/// assert!(finalized.is_finalized());
+/// ```
+pub type BoxAbortSafeFuture<'a, T> = Option<Pin<Box<ManuallyDrop<dyn AbortSafeFuture<Output = T> + 'a>>>>;
 
 pub trait AbortSafeFutureExt: AbortSafeFuture {
-    fn then<Fut, F>(self, f: F) -> Then<Self, Fut, F>
+    /// `self`完成之后，用它的结果调用`f`，再执行`f`返回的future。
+    ///
+    /// `f`可以返回任何`IntoAbortSafeFuture`，包括std的`Future`和`async`块，它们会被`Compat`包一层。
+    /// `self`完成时已经回收了自己的资源，所以`f`和它返回的future总是在`self`回收之后才执行。
+    ///
+    /// ```
+    /// use std::cell::RefCell;
+    /// use abort_safe_future::AbortSafeFutureExt;
+    /// use abort_safe_future::combinator::Compat;
+    /// use abort_safe_future::executor::block_on;
+    ///
+    /// let log = RefCell::new(Vec::new());
+    /// let log = &log;
+    /// let fut = Compat::new(async move { log.borrow_mut().push("first"); 1 })
+    ///     .then(move |x| {
+    ///         log.borrow_mut().push("then");
+    ///         async move { log.borrow_mut().push("second"); x + 1 }
+    ///     });
+    ///
+    /// assert_eq!(block_on(fut), 2);
+    /// assert_eq!(*log.borrow(), ["first", "then", "second"]);
+    /// ```
+    fn then<Fut, M, F>(self, f: F) -> Then<Self, Fut::Future, F, M>
     where
         Self: Sized,
-        Fut: AbortSafeFuture,
+        Fut: IntoAbortSafeFuture<M>,
         F: FnOnce(Self::Output) -> Fut,
     {
         Then::new(self, f)
     }
+
+    fn map<T, F>(self, f: F) -> Map<Self, F>
+    where
+        Self: Sized,
+        F: FnOnce(Self::Output) -> T,
+    {
+        Map::new(self, f)
+    }
+
+    /// 用`Into`转换结果类型
+    ///
+    /// ```
+    /// use abort_safe_future::AbortSafeFutureExt;
+    ///
+    /// let fut = abort_safe_future::ready::<u32>(5).map_into::<u64>();
+    /// assert_eq!(abort_safe_future::executor::block_on(fut), 5u64);
+    /// ```
+    fn map_into<U>(self) -> Map<Self, fn(Self::Output) -> U>
+    where
+        Self: Sized,
+        Self::Output: Into<U>,
+    {
+        Map::new(self, Into::into)
+    }
+
+    fn inspect<F>(self, f: F) -> Inspect<Self, F>
+    where
+        Self: Sized,
+        F: FnOnce(&Self::Output),
+    {
+        Inspect::new(self, f)
+    }
+
+    fn inspect_cancel<F>(self, f: F) -> InspectCancel<Self, F>
+    where
+        Self: Sized,
+        F: FnOnce(),
+    {
+        InspectCancel::new(self, f)
+    }
+
+    /// 借用`self`得到一个abort safe future，用于在不交出所有权的情况下`poll`它。
+    ///
+    /// `ByRef`不拥有`self`，所以取消`ByRef`时只会释放借用，不会调用`self`的`poll_cancel`，
+    /// `self`的所有者仍然负责回收它。
+    ///
+    /// ```
+    /// #![feature(arbitrary_self_types)]
+    /// use std::mem::ManuallyDrop;
+    /// use std::task::Poll;
+    /// use abort_safe_future::{ready, select, AbortSafeFutureExt, Either};
+    /// use abort_safe_future::executor::block_on;
+    /// use abort_safe_future::testing::{checked, poll_once};
+    ///
+    /// let mut polls = 0;
+    /// let (fut, finalized) = checked(abort_safe_future::poll_fn(move |cx| {
+    ///     polls += 1;
+    ///     if polls < 2 {
+    ///         cx.waker().wake_by_ref();
+    ///         return Poll::Pending;
+    ///     }
+    ///     Poll::Ready(polls)
+    /// }));
+    /// let mut fut = Box::pin(ManuallyDrop::new(fut));
+    ///
+    /// // `select` cancels the borrowed loser, which leaves the future itself untouched
+    /// assert_eq!(block_on(select(fut.as_mut().by_ref(), ready(()))), Either::Right(()));
+    /// assert!(!finalized.is_finalized());
+    ///
+    /// // the owner keeps driving it, from where `select` left off
+    /// assert_eq!(poll_once(fut.as_mut()), Poll::Ready(2));
+    /// assert!(finalized.is_finalized());
+    /// ```
+    fn by_ref<'a>(self: Pin<&'a mut ManuallyDrop<Self>>) -> ByRef<'a, Self> {
+        ByRef::new(self)
+    }
+
+    fn fuse(self) -> Fuse<Self>
+    where
+        Self: Sized,
+    {
+        Fuse::new(self)
+    }
+
+    fn catch_unwind(self) -> CatchUnwind<Self>
+    where
+        Self: Sized,
+    {
+        CatchUnwind::new(self)
+    }
+
+    fn into_std_future(self) -> IntoStdFuture<Self>
+    where
+        Self: Sized,
+    {
+        IntoStdFuture::new(self)
+    }
+
+    fn shared(self) -> Shared<Self>
+    where
+        Self: Sized,
+        Self::Output: Clone,
+    {
+        Shared::new(self)
+    }
+
+    fn and_then<T, U, E, Fut, M, F>(self, f: F) -> AndThen<Self, Fut::Future, F, M>
+    where
+        Self: AbortSafeFuture<Output = Result<T, E>> + Sized,
+        Fut: IntoAbortSafeFuture<M, Output = Result<U, E>>,
+        F: FnOnce(T) -> Fut,
+    {
+        AndThen::new(self, f)
+    }
+
+    fn or_else<T, E, E2, Fut, M, F>(self, f: F) -> OrElse<Self, Fut::Future, F, M>
+    where
+        Self: AbortSafeFuture<Output = Result<T, E>> + Sized,
+        Fut: IntoAbortSafeFuture<M, Output = Result<T, E2>>,
+        F: FnOnce(E) -> Fut,
+    {
+        OrElse::new(self, f)
+    }
+
+    fn map_ok<T, U, E, F>(self, f: F) -> MapOk<Self, F>
+    where
+        Self: AbortSafeFuture<Output = Result<T, E>> + Sized,
+        F: FnOnce(T) -> U,
+    {
+        MapOk::new(self, f)
+    }
+
+    fn map_err<T, E, E2, F>(self, f: F) -> MapErr<Self, F>
+    where
+        Self: AbortSafeFuture<Output = Result<T, E>> + Sized,
+        F: FnOnce(E) -> E2,
+    {
+        MapErr::new(self, f)
+    }
+
+    /// 用`Into`转换错误类型
+    ///
+    /// ```
+    /// use std::error::Error;
+    /// use std::fmt;
+    /// use abort_safe_future::AbortSafeFutureExt;
+    ///
+    /// #[derive(Debug)]
+    /// struct MyError;
+    ///
+    /// impl fmt::Display for MyError {
+    ///     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    ///         f.write_str("my error")
+    ///     }
+    /// }
+    ///
+    /// impl Error for MyError {}
+    ///
+    /// let fut = abort_safe_future::ready(Err::<u32, _>(MyError)).err_into();
+    /// let err: Box<dyn Error> = abort_safe_future::executor::block_on(fut).unwrap_err();
+    /// assert_eq!(err.to_string(), "my error");
+    /// ```
+    fn err_into<T, E, E2>(self) -> MapErr<Self, fn(E) -> E2>
+    where
+        Self: AbortSafeFuture<Output = Result<T, E>> + Sized,
+        E: Into<E2>,
+    {
+        MapErr::new(self, Into::into)
+    }
+
+    /// 结果为`Ok`时用它的引用调用`f`
+    ///
+    /// ```
+    /// use std::cell::Cell;
+    /// use abort_safe_future::AbortSafeFutureExt;
+    /// use abort_safe_future::executor::block_on;
+    ///
+    /// let seen = Cell::new(None);
+    /// let fut = abort_safe_future::ready(Ok::<u32, ()>(1)).inspect_ok(|x| seen.set(Some(*x)));
+    /// assert_eq!(block_on(fut), Ok(1));
+    /// assert_eq!(seen.get(), Some(1));
+    ///
+    /// let seen = Cell::new(None);
+    /// let fut = abort_safe_future::ready(Err::<u32, ()>(())).inspect_ok(|x| seen.set(Some(*x)));
+    /// assert_eq!(block_on(fut), Err(()));
+    /// assert_eq!(seen.get(), None);
+    /// ```
+    fn inspect_ok<T, E, F>(self, f: F) -> InspectOk<Self, F>
+    where
+        Self: AbortSafeFuture<Output = Result<T, E>> + Sized,
+        F: FnOnce(&T),
+    {
+        InspectOk::new(self, f)
+    }
+
+    /// 结果为`Err`时用它的引用调用`f`
+    ///
+    /// ```
+    /// use std::cell::Cell;
+    /// use abort_safe_future::AbortSafeFutureExt;
+    /// use abort_safe_future::executor::block_on;
+    ///
+    /// let seen = Cell::new(None);
+    /// let fut = abort_safe_future::ready(Err::<(), u32>(2)).inspect_err(|e| seen.set(Some(*e)));
+    /// assert_eq!(block_on(fut), Err(2));
+    /// assert_eq!(seen.get(), Some(2));
+    ///
+    /// let seen = Cell::new(None);
+    /// let fut = abort_safe_future::ready(Ok::<(), u32>(())).inspect_err(|e| seen.set(Some(*e)));
+    /// assert_eq!(block_on(fut), Ok(()));
+    /// assert_eq!(seen.get(), None);
+    /// ```
+    fn inspect_err<T, E, F>(self, f: F) -> InspectErr<Self, F>
+    where
+        Self: AbortSafeFuture<Output = Result<T, E>> + Sized,
+        F: FnOnce(&E),
+    {
+        InspectErr::new(self, f)
+    }
+
+    fn unwrap_or_else<T, E, F>(self, f: F) -> UnwrapOrElse<Self, F>
+    where
+        Self: AbortSafeFuture<Output = Result<T, E>> + Sized,
+        F: FnOnce(E) -> T,
+    {
+        UnwrapOrElse::new(self, f)
+    }
+
+    fn flatten(self) -> Flatten<Self>
+    where
+        Self: Sized,
+        Self::Output: AbortSafeFuture,
+    {
+        Flatten::new(self)
+    }
+
+    /// 结果为`Ok(fut)`时继续执行`fut`，为`Err`时直接返回
+    fn try_flatten<T, E, Fut2>(self) -> TryFlatten<Self, Fut2>
+    where
+        Self: AbortSafeFuture<Output = Result<Fut2, E>> + Sized,
+        Fut2: AbortSafeFuture<Output = Result<T, E>>,
+    {
+        TryFlatten::new(self)
+    }
+
+    fn race<Fut>(self, other: Fut) -> Race<Self, Fut>
+    where
+        Self: Sized,
+        Fut: AbortSafeFuture<Output = Self::Output>,
+    {
+        Race::new(self, other)
+    }
+
+    /// 无论`self`完成还是被取消，之后都把`cleanup`执行到完成
+    fn guard<C>(self, cleanup: C) -> Guard<Self, C>
+    where
+        Self: Sized,
+        C: AbortSafeFuture<Output = ()>,
+    {
+        Guard::new(self, cleanup)
+    }
+
+    /// 转换成只有一个元素的stream
+    fn into_stream(self) -> IntoStream<Self>
+    where
+        Self: Sized,
+    {
+        IntoStream::new(self)
+    }
+
+    /// 等待future完成，再产生它返回的stream中的元素
+    fn flatten_stream(self) -> FlattenStream<Self>
+    where
+        Self: Sized,
+        Self::Output: AbortSafeStream,
+    {
+        FlattenStream::new(self)
+    }
+
+    /// 用什么都不做的waker `poll`一次，完成的话返回结果，否则取消`self`并返回`None`。
+    ///
+    /// 取消时会把`poll_cancel`驱动到完成。没有waker可以用来等待，
+    /// `poll_cancel`返回`Poll::Pending`时会一直自旋，所以只适用于可以同步完成回收的future。
+    ///
+    /// ```
+    /// use abort_safe_future::AbortSafeFutureExt;
+    ///
+    /// assert_eq!(abort_safe_future::ready(5).now_or_never(), Some(5));
+    /// assert_eq!(abort_safe_future::pending::<u32>().now_or_never(), None);
+    /// ```
+    fn now_or_never(self) -> Option<Self::Output>
+    where
+        Self: Sized,
+    {
+        let mut fut = ManuallyDrop::new(self);
+        // `fut` is shadowed, so it can never be moved again
+        let mut fut = unsafe { Pin::new_unchecked(&mut fut) };
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        if let Poll::Ready(output) = fut.as_mut().poll(&mut cx) {
+            return Some(output);
+        }
+        while fut.as_mut().poll_cancel(&mut cx).is_pending() {
+            hint::spin_loop();
+        }
+        None
+    }
+
+    /// 擦除类型，得到`BoxAbortSafeFuture`
+    ///
+    /// ```
+    /// use abort_safe_future::{ready, AbortSafeFutureExt};
+    /// use abort_safe_future::executor::block_on;
+    ///
+    /// let chain = || ready(1).then(|x| ready(x + 1)).then(|x| ready(x * 10));
+    /// assert_eq!(block_on(chain().boxed()), block_on(chain()));
+    /// ```
+    fn boxed<'a>(self) -> BoxAbortSafeFuture<'a, Self::Output>
+    where
+        Self: Sized + 'a,
+    {
+        Some(Box::pin(ManuallyDrop::new(self)) as Pin<Box<ManuallyDrop<dyn AbortSafeFuture<Output = Self::Output> + 'a>>>)
+    }
 }
 
 impl<Fut: AbortSafeFuture> AbortSafeFutureExt for Fut {}
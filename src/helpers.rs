@@ -8,3 +8,9 @@ where
 {
     unsafe { Pin::new_unchecked(&mut *pin.as_mut().get_unchecked_mut()) }
 }
+
+pub fn iter_pin_mut<T>(slice: Pin<&mut [T]>) -> impl Iterator<Item = Pin<&mut T>> {
+    unsafe { slice.get_unchecked_mut() }
+        .iter_mut()
+        .map(|t| unsafe { Pin::new_unchecked(t) })
+}
@@ -63,9 +63,19 @@
 extern crate core;
 
 pub mod future;
+pub mod async_drop;
 pub mod combinator;
 pub mod executor;
+pub mod stream;
+pub mod sink;
+pub mod time;
+pub mod testing;
+pub mod dyn_support;
 pub(crate) mod helpers;
 
-pub use future::{AbortSafeFuture, AbortSafeFutureExt};
-pub use combinator::{ready, pending};
\ No newline at end of file
+pub use future::{AbortSafeFuture, AbortSafeFutureExt, BoxAbortSafeFuture, IntoAbortSafeFuture, PinManuallyDropExt};
+pub use async_drop::{AsyncDrop, SyncDrop, defer_async};
+pub use stream::{AbortSafeStream, AbortSafeStreamExt};
+pub use sink::{AbortSafeSink, AbortSafeSinkExt};
+pub use combinator::{ready, pending, join, join_biased, join3, join4, join5, try_join, join_all, select, select_biased, race, select_all, poll_fn, lazy, abortable, Either};
+pub use abort_safe_future_macros::{abort_safe, abort_safe_stream, AsyncDrop};
\ No newline at end of file
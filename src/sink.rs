@@ -0,0 +1,283 @@
+use core::task::Context;
+use core::task::Poll;
+use core::pin::Pin;
+use core::mem::ManuallyDrop;
+
+use std::marker::PhantomData;
+use std::task::ready;
+use futures::Sink;
+use pin_project::pin_project;
+use crate::future::AbortSafeFuture;
+use crate::helpers::pin_manually_drop_as_mut;
+
+
+/// abort safe sink
+///
+/// 与`AbortSafeFuture`、`AbortSafeStream`一致，资源的回收通过`poll_close`或者`poll_cancel`完成，
+/// 而不是要求实现`AsyncDrop`：sink被`Pin<&mut ManuallyDrop<_>>`保护，所有者直接调用这两个方法即可。
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use futures::StreamExt;
+/// use abort_safe_future::AbortSafeSinkExt;
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::sink::Compat;
+///
+/// let (tx, rx) = futures::channel::mpsc::unbounded::<u32>();
+/// let mut sink = Box::pin(ManuallyDrop::new(Compat::new(tx)));
+/// for item in [1, 2, 3] {
+///     block_on(sink.as_mut().send(item)).unwrap();
+/// }
+/// block_on(sink.as_mut().close()).unwrap();
+/// assert_eq!(futures::executor::block_on(rx.collect::<Vec<_>>()), [1, 2, 3]);
+/// ```
+///
+/// 取消一个已经把元素交给sink的`send`时，会先把它flush出去：
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::cell::RefCell;
+/// use std::mem::ManuallyDrop;
+/// use std::pin::Pin;
+/// use std::rc::Rc;
+/// use std::task::{Context, Poll};
+/// use abort_safe_future::{AbortSafeSink, AbortSafeSinkExt};
+/// use abort_safe_future::testing::{poll_cancel_once, poll_once};
+///
+/// // `poll_flush` only makes progress on every other call
+/// struct Buffered {
+///     buffer: Vec<u32>,
+///     flushed: Rc<RefCell<Vec<u32>>>,
+///     stalled: bool,
+/// }
+///
+/// impl AbortSafeSink<u32> for Buffered {
+///     type Error = ();
+///
+///     fn poll_ready(self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+///         Poll::Ready(Ok(()))
+///     }
+///
+///     fn start_send(mut self: Pin<&mut ManuallyDrop<Self>>, item: u32) -> Result<(), ()> {
+///         self.buffer.push(item);
+///         Ok(())
+///     }
+///
+///     fn poll_flush(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+///         self.stalled = !self.stalled;
+///         if self.stalled {
+///             cx.waker().wake_by_ref();
+///             return Poll::Pending;
+///         }
+///         let items: Vec<_> = self.buffer.drain(..).collect();
+///         self.flushed.borrow_mut().extend(items);
+///         Poll::Ready(Ok(()))
+///     }
+///
+///     fn poll_close(self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+///         self.poll_flush(cx)
+///     }
+///
+///     fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+///         self.buffer.clear();
+///         Poll::Ready(())
+///     }
+/// }
+///
+/// let flushed = Rc::new(RefCell::new(Vec::new()));
+/// let mut sink = Box::pin(ManuallyDrop::new(Buffered { buffer: Vec::new(), flushed: flushed.clone(), stalled: false }));
+/// let mut send = Box::pin(ManuallyDrop::new(sink.as_mut().send(7)));
+/// // the item is in the buffer, the flush is still pending
+/// assert!(poll_once(send.as_mut()).is_pending());
+/// assert!(flushed.borrow().is_empty());
+/// assert!(poll_cancel_once(send.as_mut()).is_ready());
+/// assert_eq!(*flushed.borrow(), [7]);
+/// ```
+pub trait AbortSafeSink<Item> {
+    type Error;
+
+    /// 准备好接收下一个元素时返回`Poll::Ready(Ok(()))`
+    fn poll_ready(self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>>;
+
+    /// 只能在`poll_ready`返回`Poll::Ready(Ok(()))`之后调用
+    fn start_send(self: Pin<&mut ManuallyDrop<Self>>, item: Item) -> Result<(), Self::Error>;
+
+    /// 把缓存的元素全部写出去
+    fn poll_flush(self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>>;
+
+    /// 写出缓存的元素并关闭sink。返回`Poll::Ready`时应该已经完成了所有资源的回收
+    fn poll_close(self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>>;
+
+    /// 在关闭之前中断时，调用此方法，直到其返回`Poll::Ready`。否则可能内存泄漏。
+    /// 还没有写出的元素可能会丢失
+    fn poll_cancel(self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()>;
+}
+
+
+#[pin_project]
+pub struct Compat<Si> {
+    #[pin]
+    inner: Option<Si>,
+}
+
+impl<Si> Compat<Si> {
+    pub fn new(inner: Si) -> Self {
+        Self {
+            inner: Some(inner),
+        }
+    }
+
+    fn inner(self: Pin<&mut ManuallyDrop<Self>>) -> Pin<&mut Si> {
+        // `ManuallyDrop<Self>` never moves `Self`
+        let this = unsafe { self.map_unchecked_mut(|this| &mut **this) };
+        match this.project().inner.as_pin_mut() {
+            Some(inner) => inner,
+            None => panic!("sink::Compat used after close or after cancel"),
+        }
+    }
+}
+
+/// 所有Sink都是abort safe的
+impl<Si: Sink<Item>, Item> AbortSafeSink<Item> for Compat<Si> {
+    type Error = Si::Error;
+
+    fn poll_ready(self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner().poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut ManuallyDrop<Self>>, item: Item) -> Result<(), Self::Error> {
+        self.inner().start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner().poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let result = ready!(self.as_mut().inner().poll_close(cx));
+        // drop inner sink
+        pin_manually_drop_as_mut(&mut self).project().inner.set(None);
+        Poll::Ready(result)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+        // drop inner sink
+        pin_manually_drop_as_mut(&mut self).project().inner.set(None);
+        Poll::Ready(())
+    }
+}
+
+
+/// `AbortSafeSinkExt::send`返回的future。
+///
+/// `Send`只是借用了sink。取消时如果元素已经交给了sink，会先`poll_flush`直到完成，
+/// 然后只释放借用，sink仍然由它的所有者负责回收。
+pub struct Send<'a, Si: ?Sized, Item> {
+    sink: Option<Pin<&'a mut ManuallyDrop<Si>>>,
+    item: Option<Item>,
+}
+
+impl<'a, Si: ?Sized, Item> Send<'a, Si, Item> {
+    pub fn new(sink: Pin<&'a mut ManuallyDrop<Si>>, item: Item) -> Self {
+        Self {
+            sink: Some(sink),
+            item: Some(item),
+        }
+    }
+}
+
+impl<Si: AbortSafeSink<Item> + ?Sized, Item> AbortSafeFuture for Send<'_, Si, Item> {
+    type Output = Result<(), Si::Error>;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // `Send` never pins its fields
+        let this: &mut Send<'_, Si, Item> = unsafe { pin_manually_drop_as_mut(&mut self).get_unchecked_mut() };
+        let sink = match &mut this.sink {
+            Some(sink) => sink,
+            None => panic!("Send::poll called after completion or after cancel"),
+        };
+
+        if this.item.is_some() {
+            if let Err(e) = ready!(sink.as_mut().poll_ready(cx)) {
+                this.item = None;
+                this.sink = None;
+                return Poll::Ready(Err(e));
+            }
+
+            if let Err(e) = sink.as_mut().start_send(this.item.take().unwrap()) {
+                this.sink = None;
+                return Poll::Ready(Err(e));
+            }
+        }
+
+        let result = ready!(sink.as_mut().poll_flush(cx));
+        this.sink = None;
+        Poll::Ready(result)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let this: &mut Send<'_, Si, Item> = unsafe { pin_manually_drop_as_mut(&mut self).get_unchecked_mut() };
+        if let Some(sink) = &mut this.sink {
+            if this.item.is_none() {
+                // the item has been handed to the sink, don't leave it in the buffer
+                let _ = ready!(sink.as_mut().poll_flush(cx));
+            }
+        }
+
+        // drop the unsent item and release the borrow
+        this.item = None;
+        this.sink = None;
+        Poll::Ready(())
+    }
+}
+
+
+/// `AbortSafeSinkExt::close`返回的future。取消时只释放借用
+pub struct Close<'a, Si: ?Sized, Item> {
+    sink: Option<Pin<&'a mut ManuallyDrop<Si>>>,
+    _item: PhantomData<fn(Item)>,
+}
+
+impl<'a, Si: ?Sized, Item> Close<'a, Si, Item> {
+    pub fn new(sink: Pin<&'a mut ManuallyDrop<Si>>) -> Self {
+        Self {
+            sink: Some(sink),
+            _item: PhantomData,
+        }
+    }
+}
+
+impl<Si: AbortSafeSink<Item> + ?Sized, Item> AbortSafeFuture for Close<'_, Si, Item> {
+    type Output = Result<(), Si::Error>;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let sink = match &mut self.sink {
+            Some(sink) => sink,
+            None => panic!("Close::poll called after completion or after cancel"),
+        };
+
+        let result = ready!(sink.as_mut().poll_close(cx));
+        self.sink = None;
+        Poll::Ready(result)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+        // only release the borrow, the owner of the sink is responsible for cancelling it
+        self.sink = None;
+        Poll::Ready(())
+    }
+}
+
+
+pub trait AbortSafeSinkExt<Item>: AbortSafeSink<Item> {
+    fn send<'a>(self: Pin<&'a mut ManuallyDrop<Self>>, item: Item) -> Send<'a, Self, Item> {
+        Send::new(self, item)
+    }
+
+    fn close<'a>(self: Pin<&'a mut ManuallyDrop<Self>>) -> Close<'a, Self, Item> {
+        Close::new(self)
+    }
+}
+
+impl<Si: AbortSafeSink<Item> + ?Sized, Item> AbortSafeSinkExt<Item> for Si {}
@@ -0,0 +1,2666 @@
+use core::task::Context;
+use core::task::Poll;
+use core::pin::Pin;
+use core::mem::ManuallyDrop;
+
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{ready, Wake, Waker};
+use futures::Stream;
+use pin_project::pin_project;
+use crate::future::AbortSafeFuture;
+use crate::helpers::pin_manually_drop_as_mut;
+
+
+/// abort safe stream
+pub trait AbortSafeStream {
+    /// Stream中元素的类型
+    type Item;
+    /// 与`AbortSafeFuture::poll`类似。
+    /// 返回`Poll::Ready(None)`时表示stream已经结束，此时应该已经完成了所有资源的回收。
+    fn poll_next(self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>>;
+
+    /// 在stream结束之前中断时，调用此方法，直到其返回`Poll::Ready`。否则可能内存泄漏。
+    fn poll_cancel(self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()>;
+}
+
+
+/// 把`futures::Stream`包装成`AbortSafeStream`
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use std::task::{Context, Poll};
+/// use abort_safe_future::AbortSafeStream;
+/// use abort_safe_future::stream::Compat;
+/// use abort_safe_future::testing::noop_waker;
+///
+/// let waker = noop_waker();
+/// let mut cx = Context::from_waker(&waker);
+/// let mut stream = Box::pin(ManuallyDrop::new(Compat::new(futures::stream::iter(vec![1, 2, 3]))));
+/// for i in 1..=3 {
+///     assert_eq!(stream.as_mut().poll_next(&mut cx), Poll::Ready(Some(i)));
+/// }
+/// assert_eq!(stream.as_mut().poll_next(&mut cx), Poll::Ready(None));
+/// ```
+#[pin_project]
+pub struct Compat<St> {
+    #[pin]
+    inner: Option<St>,
+}
+
+impl<St> Compat<St> {
+    pub fn new(inner: St) -> Self {
+        Self {
+            inner: Some(inner),
+        }
+    }
+}
+
+/// 所有Stream都是abort safe的
+impl<St: Stream> AbortSafeStream for Compat<St> {
+    type Item = St::Item;
+
+    fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        let item = if let Some(st) = this.inner.as_mut().as_pin_mut() {
+            ready!(st.poll_next(cx))
+        } else {
+            panic!("Compat::poll_next called after completion or after cancel")
+        };
+
+        if item.is_none() {
+            // drop inner stream
+            this.inner.set(None);
+        }
+        Poll::Ready(item)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        // drop inner stream
+        this.inner.set(None);
+        Poll::Ready(())
+    }
+}
+
+
+/// `AbortSafeStreamExt::next`返回的future。
+///
+/// `Next`只是借用了stream，stream的所有权仍然在调用者手上。
+/// 所以取消`Next`时不会调用stream的`poll_cancel`，stream之后还可以继续使用，并由它的所有者负责回收。
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use abort_safe_future::AbortSafeStreamExt;
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::Compat;
+///
+/// let mut stream = Box::pin(ManuallyDrop::new(Compat::new(futures::stream::iter(vec![1, 2, 3]))));
+/// assert_eq!(block_on(stream.as_mut().next()), Some(1));
+/// assert_eq!(block_on(stream.as_mut().next()), Some(2));
+/// assert_eq!(block_on(stream.as_mut().next()), Some(3));
+/// assert_eq!(block_on(stream.as_mut().next()), None);
+/// ```
+pub struct Next<'a, St: ?Sized> {
+    stream: Option<Pin<&'a mut ManuallyDrop<St>>>,
+}
+
+impl<'a, St: ?Sized> Next<'a, St> {
+    pub fn new(stream: Pin<&'a mut ManuallyDrop<St>>) -> Self {
+        Self { stream: Some(stream) }
+    }
+}
+
+impl<St: AbortSafeStream + ?Sized> AbortSafeFuture for Next<'_, St> {
+    type Output = Option<St::Item>;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => panic!("Next::poll called after completion or after cancel"),
+        };
+
+        let item = ready!(stream.as_mut().poll_next(cx));
+        self.stream = None;
+        Poll::Ready(item)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+        // only release the borrow, the owner of the stream is responsible for cancelling it
+        self.stream = None;
+        Poll::Ready(())
+    }
+}
+
+
+/// 对stream的每一个元素调用`F`，并执行得到的future，上一个future完成之后才会取下一个元素
+///
+/// ```
+/// use std::cell::{Cell, RefCell};
+/// use abort_safe_future::{lazy, AbortSafeStreamExt};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::Compat;
+///
+/// let calls = Cell::new(0);
+/// let seen = RefCell::new(Vec::new());
+/// let fut = Compat::new(futures::stream::iter(vec![1, 2, 3])).for_each(|x| {
+///     calls.set(calls.get() + 1);
+///     let seen = &seen;
+///     lazy(move |_| seen.borrow_mut().push(x))
+/// });
+/// block_on(fut);
+/// assert_eq!(calls.get(), 3);
+/// assert_eq!(*seen.borrow(), [1, 2, 3]);
+/// ```
+#[pin_project]
+pub struct ForEach<St, Fut, F> {
+    #[pin]
+    stream: Option<ManuallyDrop<St>>,
+    #[pin]
+    fut: Option<ManuallyDrop<Fut>>,
+    f: Option<F>,
+}
+
+impl<St, Fut, F> ForEach<St, Fut, F> {
+    pub fn new(stream: St, f: F) -> Self {
+        Self {
+            stream: Some(ManuallyDrop::new(stream)),
+            fut: None,
+            f: Some(f),
+        }
+    }
+}
+
+impl<St, Fut, F> AbortSafeFuture for ForEach<St, Fut, F>
+where
+    St: AbortSafeStream,
+    Fut: AbortSafeFuture<Output = ()>,
+    F: FnMut(St::Item) -> Fut,
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        loop {
+            if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+                ready!(fut.poll(cx));
+                this.fut.set(None);
+            }
+
+            let stream = match this.stream.as_mut().as_pin_mut() {
+                Some(stream) => stream,
+                None => panic!("ForEach::poll called after completion or after cancel"),
+            };
+
+            match ready!(stream.poll_next(cx)) {
+                Some(item) => {
+                    let fut = (this.f.as_mut().unwrap())(item);
+                    this.fut.set(Some(ManuallyDrop::new(fut)));
+                }
+                None => {
+                    this.stream.set(None);
+                    // drop closure
+                    *this.f = None;
+                    return Poll::Ready(());
+                }
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+            if fut.poll_cancel(cx).is_ready() {
+                this.fut.set(None);
+            }
+        }
+
+        if let Some(stream) = this.stream.as_mut().as_pin_mut() {
+            if stream.poll_cancel(cx).is_ready() {
+                this.stream.set(None);
+            }
+        }
+
+        if this.fut.is_some() || this.stream.is_some() {
+            return Poll::Pending;
+        }
+
+        // drop closure
+        *this.f = None;
+        Poll::Ready(())
+    }
+}
+
+
+/// 把stream中所有的元素收集到`C`中
+///
+/// ```
+/// use abort_safe_future::AbortSafeStreamExt;
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::Compat;
+///
+/// let items: Vec<u32> = block_on(Compat::new(futures::stream::iter(vec![1, 2, 3])).collect());
+/// assert_eq!(items, [1, 2, 3]);
+/// ```
+///
+/// 中途取消时，stream会被取消，已经收集到的元素会被析构：
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use std::rc::Rc;
+/// use futures::StreamExt;
+/// use abort_safe_future::AbortSafeStreamExt as _;
+/// use abort_safe_future::stream::Compat;
+/// use abort_safe_future::testing::{poll_cancel_once, poll_once};
+///
+/// let item = Rc::new(());
+/// let resource = Rc::new(());
+/// let held = resource.clone();
+/// let source = futures::stream::iter(vec![item.clone(), item.clone()])
+///     .chain(futures::stream::pending())
+///     .map(move |x| {
+///         let _ = &held;
+///         x
+///     });
+/// let mut fut = Box::pin(ManuallyDrop::new(Compat::new(source).collect::<Vec<_>>()));
+/// assert!(poll_once(fut.as_mut()).is_pending());
+/// assert_eq!(Rc::strong_count(&item), 3);
+/// assert!(poll_cancel_once(fut.as_mut()).is_ready());
+/// assert_eq!(Rc::strong_count(&item), 1);
+/// assert_eq!(Rc::strong_count(&resource), 1);
+/// ```
+#[pin_project]
+pub struct Collect<St, C> {
+    #[pin]
+    stream: Option<ManuallyDrop<St>>,
+    items: Option<C>,
+}
+
+impl<St, C: Default> Collect<St, C> {
+    pub fn new(stream: St) -> Self {
+        Self {
+            stream: Some(ManuallyDrop::new(stream)),
+            items: Some(C::default()),
+        }
+    }
+}
+
+impl<St, C> AbortSafeFuture for Collect<St, C>
+where
+    St: AbortSafeStream,
+    C: Default + Extend<St::Item>,
+{
+    type Output = C;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        loop {
+            let stream = match this.stream.as_mut().as_pin_mut() {
+                Some(stream) => stream,
+                None => panic!("Collect::poll called after completion or after cancel"),
+            };
+
+            match ready!(stream.poll_next(cx)) {
+                Some(item) => this.items.as_mut().unwrap().extend(Some(item)),
+                None => {
+                    this.stream.set(None);
+                    return Poll::Ready(this.items.take().unwrap());
+                }
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(stream) = this.stream.as_mut().as_pin_mut() {
+            ready!(stream.poll_cancel(cx));
+            this.stream.set(None);
+        }
+
+        // drop collected items
+        *this.items = None;
+        Poll::Ready(())
+    }
+}
+
+
+/// 取出stream中的所有元素并计数，stream结束时返回元素个数
+///
+/// ```
+/// use abort_safe_future::AbortSafeStreamExt;
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::Compat;
+///
+/// let stream = Compat::new(futures::stream::iter(vec!['a', 'b', 'c', 'd']));
+/// assert_eq!(block_on(stream.count()), 4);
+/// ```
+#[pin_project]
+pub struct Count<St> {
+    #[pin]
+    stream: Option<ManuallyDrop<St>>,
+    count: usize,
+}
+
+impl<St> Count<St> {
+    pub fn new(stream: St) -> Self {
+        Self {
+            stream: Some(ManuallyDrop::new(stream)),
+            count: 0,
+        }
+    }
+}
+
+impl<St: AbortSafeStream> AbortSafeFuture for Count<St> {
+    type Output = usize;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        loop {
+            let stream = match this.stream.as_mut().as_pin_mut() {
+                Some(stream) => stream,
+                None => panic!("Count::poll called after completion or after cancel"),
+            };
+
+            match ready!(stream.poll_next(cx)) {
+                Some(_) => *this.count += 1,
+                None => {
+                    this.stream.set(None);
+                    return Poll::Ready(*this.count);
+                }
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(stream) = this.stream.as_mut().as_pin_mut() {
+            ready!(stream.poll_cancel(cx));
+            this.stream.set(None);
+        }
+
+        Poll::Ready(())
+    }
+}
+
+
+/// 把stream中的所有元素拼接成一个，stream为空时返回`Item::default()`
+///
+/// ```
+/// use abort_safe_future::AbortSafeStreamExt;
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::Compat;
+///
+/// let stream = Compat::new(futures::stream::iter(vec![vec![1u8, 2], vec![], vec![3, 4, 5]]));
+/// assert_eq!(block_on(stream.concat()), [1, 2, 3, 4, 5]);
+/// ```
+#[pin_project]
+pub struct Concat<St: AbortSafeStream> {
+    #[pin]
+    stream: Option<ManuallyDrop<St>>,
+    accum: Option<St::Item>,
+}
+
+impl<St: AbortSafeStream> Concat<St> {
+    pub fn new(stream: St) -> Self {
+        Self {
+            stream: Some(ManuallyDrop::new(stream)),
+            accum: None,
+        }
+    }
+}
+
+impl<St> AbortSafeFuture for Concat<St>
+where
+    St: AbortSafeStream,
+    St::Item: Default + Extend<<St::Item as IntoIterator>::Item> + IntoIterator,
+{
+    type Output = St::Item;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        loop {
+            let stream = match this.stream.as_mut().as_pin_mut() {
+                Some(stream) => stream,
+                None => panic!("Concat::poll called after completion or after cancel"),
+            };
+
+            match ready!(stream.poll_next(cx)) {
+                Some(item) => match this.accum {
+                    Some(accum) => accum.extend(item),
+                    None => *this.accum = Some(item),
+                },
+                None => {
+                    this.stream.set(None);
+                    return Poll::Ready(this.accum.take().unwrap_or_default());
+                }
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(stream) = this.stream.as_mut().as_pin_mut() {
+            ready!(stream.poll_cancel(cx));
+            this.stream.set(None);
+        }
+
+        // drop concatenated items
+        *this.accum = None;
+        Poll::Ready(())
+    }
+}
+
+
+/// 把元素为`(A, B)`的stream拆成两个`Vec`
+///
+/// ```
+/// use abort_safe_future::AbortSafeStreamExt;
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::Compat;
+///
+/// let stream = Compat::new(futures::stream::iter(vec![(1u8, 'a'), (2, 'b'), (3, 'c')]));
+/// let (numbers, chars) = block_on(stream.unzip());
+/// assert_eq!(numbers, [1, 2, 3]);
+/// assert_eq!(chars, ['a', 'b', 'c']);
+/// ```
+#[pin_project]
+pub struct Unzip<St, A, B> {
+    #[pin]
+    stream: Option<ManuallyDrop<St>>,
+    items: Option<(Vec<A>, Vec<B>)>,
+}
+
+impl<St, A, B> Unzip<St, A, B> {
+    pub fn new(stream: St) -> Self {
+        Self {
+            stream: Some(ManuallyDrop::new(stream)),
+            items: Some((Vec::new(), Vec::new())),
+        }
+    }
+}
+
+impl<St, A, B> AbortSafeFuture for Unzip<St, A, B>
+where
+    St: AbortSafeStream<Item = (A, B)>,
+{
+    type Output = (Vec<A>, Vec<B>);
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        loop {
+            let stream = match this.stream.as_mut().as_pin_mut() {
+                Some(stream) => stream,
+                None => panic!("Unzip::poll called after completion or after cancel"),
+            };
+
+            match ready!(stream.poll_next(cx)) {
+                Some((a, b)) => {
+                    let (left, right) = this.items.as_mut().unwrap();
+                    left.push(a);
+                    right.push(b);
+                }
+                None => {
+                    this.stream.set(None);
+                    return Poll::Ready(this.items.take().unwrap());
+                }
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(stream) = this.stream.as_mut().as_pin_mut() {
+            ready!(stream.poll_cancel(cx));
+            this.stream.set(None);
+        }
+
+        // drop collected items
+        *this.items = None;
+        Poll::Ready(())
+    }
+}
+
+
+/// 从初始状态`init`和闭包`f`构造stream。
+///
+/// 每次`poll_next`都会用当前的状态调用`f`并执行返回的future，
+/// 结果为`Some((item, state))`时产生`item`并保存新的状态，为`None`时stream结束。
+///
+/// ```
+/// use abort_safe_future::{ready, AbortSafeStreamExt};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::unfold;
+///
+/// let countdown = unfold(3, |n| ready(if n == 0 { None } else { Some((n, n - 1)) }));
+/// let items: Vec<u32> = block_on(countdown.collect());
+/// assert_eq!(items, [3, 2, 1]);
+/// ```
+pub fn unfold<T, F, Fut, Item>(init: T, f: F) -> Unfold<T, F, Fut>
+where
+    F: FnMut(T) -> Fut,
+    Fut: AbortSafeFuture<Output = Option<(Item, T)>>,
+{
+    Unfold::new(init, f)
+}
+
+#[pin_project]
+pub struct Unfold<T, F, Fut> {
+    state: Option<T>,
+    f: Option<F>,
+    #[pin]
+    fut: Option<ManuallyDrop<Fut>>,
+}
+
+impl<T, F, Fut> Unfold<T, F, Fut> {
+    pub fn new(init: T, f: F) -> Self {
+        Self {
+            state: Some(init),
+            f: Some(f),
+            fut: None,
+        }
+    }
+}
+
+impl<T, F, Fut, Item> AbortSafeStream for Unfold<T, F, Fut>
+where
+    F: FnMut(T) -> Fut,
+    Fut: AbortSafeFuture<Output = Option<(Item, T)>>,
+{
+    type Item = Item;
+
+    fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        if let Some(state) = this.state.take() {
+            let f = match this.f.as_mut() {
+                Some(f) => f,
+                None => panic!("Unfold::poll_next called after completion or after cancel"),
+            };
+            this.fut.set(Some(ManuallyDrop::new(f(state))));
+        }
+
+        let fut = match this.fut.as_mut().as_pin_mut() {
+            Some(fut) => fut,
+            None => panic!("Unfold::poll_next called after completion or after cancel"),
+        };
+
+        let step = ready!(fut.poll(cx));
+        this.fut.set(None);
+        match step {
+            Some((item, state)) => {
+                *this.state = Some(state);
+                Poll::Ready(Some(item))
+            }
+            None => {
+                // drop closure
+                *this.f = None;
+                Poll::Ready(None)
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+            ready!(fut.poll_cancel(cx));
+            this.fut.set(None);
+        }
+
+        // drop state and closure
+        *this.state = None;
+        *this.f = None;
+        Poll::Ready(())
+    }
+}
+
+
+/// 没有任何元素的stream
+pub fn empty<T>() -> Empty<T> {
+    Empty {
+        ended: false,
+        _marker: PhantomData,
+    }
+}
+
+pub struct Empty<T> {
+    ended: bool,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> AbortSafeStream for Empty<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = pin_manually_drop_as_mut(&mut self).get_mut();
+        if this.ended {
+            panic!("Empty::poll_next called after completion or after cancel")
+        }
+
+        this.ended = true;
+        Poll::Ready(None)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).get_mut();
+        this.ended = true;
+        Poll::Ready(())
+    }
+}
+
+
+/// 不断产生`item`的克隆，永远不会结束
+///
+/// ```
+/// use abort_safe_future::AbortSafeStreamExt;
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::repeat;
+///
+/// let items: Vec<_> = block_on(repeat(1).take(3).collect());
+/// assert_eq!(items, [1, 1, 1]);
+/// ```
+pub fn repeat<T: Clone>(item: T) -> Repeat<T> {
+    Repeat { item: Some(item) }
+}
+
+pub struct Repeat<T> {
+    item: Option<T>,
+}
+
+impl<T> Unpin for Repeat<T> {}
+
+impl<T: Clone> AbortSafeStream for Repeat<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = pin_manually_drop_as_mut(&mut self).get_mut();
+        match &this.item {
+            Some(item) => Poll::Ready(Some(item.clone())),
+            None => panic!("Repeat::poll_next called after cancel"),
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).get_mut();
+        // drop item
+        this.item = None;
+        Poll::Ready(())
+    }
+}
+
+/// 不断调用`f`产生元素，永远不会结束
+pub fn repeat_with<T, F: FnMut() -> T>(f: F) -> RepeatWith<F> {
+    RepeatWith { f: Some(f) }
+}
+
+pub struct RepeatWith<F> {
+    f: Option<F>,
+}
+
+impl<F> Unpin for RepeatWith<F> {}
+
+impl<T, F: FnMut() -> T> AbortSafeStream for RepeatWith<F> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = pin_manually_drop_as_mut(&mut self).get_mut();
+        match &mut this.f {
+            Some(f) => Poll::Ready(Some(f())),
+            None => panic!("RepeatWith::poll_next called after cancel"),
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).get_mut();
+        // drop closure
+        this.f = None;
+        Poll::Ready(())
+    }
+}
+
+
+/// `AbortSafeFutureExt::into_stream`返回的stream，只产生future的结果这一个元素
+///
+/// ```
+/// use abort_safe_future::{AbortSafeFutureExt, AbortSafeStreamExt};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::Compat;
+///
+/// let stream = Compat::new(futures::stream::iter(vec![1, 2])).chain(abort_safe_future::ready(3).into_stream());
+/// let items: Vec<_> = block_on(stream.collect());
+/// assert_eq!(items, [1, 2, 3]);
+/// ```
+#[pin_project]
+pub struct IntoStream<Fut> {
+    #[pin]
+    fut: Option<ManuallyDrop<Fut>>,
+    ended: bool,
+}
+
+impl<Fut> IntoStream<Fut> {
+    pub fn new(fut: Fut) -> Self {
+        Self {
+            fut: Some(ManuallyDrop::new(fut)),
+            ended: false,
+        }
+    }
+}
+
+impl<Fut: AbortSafeFuture> AbortSafeStream for IntoStream<Fut> {
+    type Item = Fut::Output;
+
+    fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if *this.ended {
+            panic!("IntoStream::poll_next called after completion or after cancel")
+        }
+
+        match this.fut.as_mut().as_pin_mut() {
+            Some(fut) => {
+                let output = ready!(fut.poll(cx));
+                this.fut.set(None);
+                Poll::Ready(Some(output))
+            }
+            None => {
+                *this.ended = true;
+                Poll::Ready(None)
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+            ready!(fut.poll_cancel(cx));
+            this.fut.set(None);
+        }
+
+        *this.ended = true;
+        Poll::Ready(())
+    }
+}
+
+
+/// `AbortSafeFutureExt::flatten_stream`返回的stream，先等待future完成，再产生它返回的stream中的元素
+///
+/// ```
+/// use abort_safe_future::{AbortSafeFutureExt, AbortSafeStreamExt};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::Compat;
+///
+/// let fut = abort_safe_future::ready(Compat::new(futures::stream::iter(vec![1, 2, 3])));
+/// let items: Vec<_> = block_on(fut.flatten_stream().collect());
+/// assert_eq!(items, [1, 2, 3]);
+/// ```
+#[pin_project]
+pub struct FlattenStream<Fut: AbortSafeFuture> {
+    #[pin]
+    fut: Option<ManuallyDrop<Fut>>,
+    #[pin]
+    stream: Option<ManuallyDrop<Fut::Output>>,
+}
+
+impl<Fut: AbortSafeFuture> FlattenStream<Fut> {
+    pub fn new(fut: Fut) -> Self {
+        Self {
+            fut: Some(ManuallyDrop::new(fut)),
+            stream: None,
+        }
+    }
+}
+
+impl<Fut> AbortSafeStream for FlattenStream<Fut>
+where
+    Fut: AbortSafeFuture,
+    Fut::Output: AbortSafeStream,
+{
+    type Item = <Fut::Output as AbortSafeStream>::Item;
+
+    fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+            let stream = ready!(fut.poll(cx));
+            this.fut.set(None);
+            this.stream.set(Some(ManuallyDrop::new(stream)));
+        }
+
+        let stream = match this.stream.as_mut().as_pin_mut() {
+            Some(stream) => stream,
+            None => panic!("FlattenStream::poll_next called after completion or after cancel"),
+        };
+
+        let item = ready!(stream.poll_next(cx));
+        if item.is_none() {
+            this.stream.set(None);
+        }
+        Poll::Ready(item)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+            ready!(fut.poll_cancel(cx));
+            this.fut.set(None);
+        }
+
+        if let Some(stream) = this.stream.as_mut().as_pin_mut() {
+            ready!(stream.poll_cancel(cx));
+            this.stream.set(None);
+        }
+
+        Poll::Ready(())
+    }
+}
+
+
+/// 用`F`同步地转换每一个元素
+///
+/// ```
+/// use abort_safe_future::AbortSafeStreamExt;
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::Compat;
+///
+/// let stream = Compat::new(futures::stream::iter(vec![1, 2, 3])).map(|x: i32| x.to_string());
+/// let items: Vec<String> = block_on(stream.collect());
+/// assert_eq!(items, ["1", "2", "3"]);
+/// ```
+#[pin_project]
+pub struct Map<St, F> {
+    #[pin]
+    stream: Option<ManuallyDrop<St>>,
+    f: Option<F>,
+}
+
+impl<St, F> Map<St, F> {
+    pub fn new(stream: St, f: F) -> Self {
+        Self {
+            stream: Some(ManuallyDrop::new(stream)),
+            f: Some(f),
+        }
+    }
+}
+
+impl<St, F, T> AbortSafeStream for Map<St, F>
+where
+    St: AbortSafeStream,
+    F: FnMut(St::Item) -> T,
+{
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        let stream = match this.stream.as_mut().as_pin_mut() {
+            Some(stream) => stream,
+            None => panic!("Map::poll_next called after completion or after cancel"),
+        };
+
+        match ready!(stream.poll_next(cx)) {
+            Some(item) => Poll::Ready(Some((this.f.as_mut().unwrap())(item))),
+            None => {
+                this.stream.set(None);
+                // drop closure
+                *this.f = None;
+                Poll::Ready(None)
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(stream) = this.stream.as_mut().as_pin_mut() {
+            ready!(stream.poll_cancel(cx));
+            this.stream.set(None);
+        }
+
+        // drop closure
+        *this.f = None;
+        Poll::Ready(())
+    }
+}
+
+
+/// 只保留异步的判断条件返回`true`的元素
+///
+/// ```
+/// use abort_safe_future::{ready, AbortSafeStreamExt};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::Compat;
+///
+/// let stream = Compat::new(futures::stream::iter(1..=5)).filter(|x: &i32| ready(x % 2 == 0));
+/// let items: Vec<_> = block_on(stream.collect());
+/// assert_eq!(items, [2, 4]);
+/// ```
+#[pin_project]
+pub struct Filter<St: AbortSafeStream, Fut, F> {
+    #[pin]
+    stream: Option<ManuallyDrop<St>>,
+    #[pin]
+    fut: Option<ManuallyDrop<Fut>>,
+    item: Option<St::Item>,
+    f: Option<F>,
+}
+
+impl<St: AbortSafeStream, Fut, F> Filter<St, Fut, F> {
+    pub fn new(stream: St, f: F) -> Self {
+        Self {
+            stream: Some(ManuallyDrop::new(stream)),
+            fut: None,
+            item: None,
+            f: Some(f),
+        }
+    }
+}
+
+impl<St, Fut, F> AbortSafeStream for Filter<St, Fut, F>
+where
+    St: AbortSafeStream,
+    Fut: AbortSafeFuture<Output = bool>,
+    F: FnMut(&St::Item) -> Fut,
+{
+    type Item = St::Item;
+
+    fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        loop {
+            if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+                let keep = ready!(fut.poll(cx));
+                this.fut.set(None);
+                let item = this.item.take().unwrap();
+                if keep {
+                    return Poll::Ready(Some(item));
+                }
+            }
+
+            let stream = match this.stream.as_mut().as_pin_mut() {
+                Some(stream) => stream,
+                None => panic!("Filter::poll_next called after completion or after cancel"),
+            };
+
+            match ready!(stream.poll_next(cx)) {
+                Some(item) => {
+                    let fut = (this.f.as_mut().unwrap())(&item);
+                    *this.item = Some(item);
+                    this.fut.set(Some(ManuallyDrop::new(fut)));
+                }
+                None => {
+                    this.stream.set(None);
+                    // drop closure
+                    *this.f = None;
+                    return Poll::Ready(None);
+                }
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+            if fut.poll_cancel(cx).is_ready() {
+                this.fut.set(None);
+            }
+        }
+
+        if let Some(stream) = this.stream.as_mut().as_pin_mut() {
+            if stream.poll_cancel(cx).is_ready() {
+                this.stream.set(None);
+            }
+        }
+
+        if this.fut.is_some() || this.stream.is_some() {
+            return Poll::Pending;
+        }
+
+        // drop the pending item and closure
+        *this.item = None;
+        *this.f = None;
+        Poll::Ready(())
+    }
+}
+
+
+/// 产生元素直到`f`第一次返回`false`，之后先取消内部的stream，再结束
+///
+/// ```
+/// use abort_safe_future::AbortSafeStreamExt;
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::Compat;
+///
+/// let stream = Compat::new(futures::stream::iter(vec![1, 2, 3, 4, 5]))
+///     .take_while(|x: &i32| abort_safe_future::ready(*x < 3));
+/// let items: Vec<_> = block_on(stream.collect());
+/// assert_eq!(items, [1, 2]);
+/// ```
+#[pin_project]
+pub struct TakeWhile<St: AbortSafeStream, Fut, F> {
+    #[pin]
+    stream: Option<ManuallyDrop<St>>,
+    #[pin]
+    fut: Option<ManuallyDrop<Fut>>,
+    item: Option<St::Item>,
+    f: Option<F>,
+    done: bool,
+}
+
+impl<St: AbortSafeStream, Fut, F> TakeWhile<St, Fut, F> {
+    pub fn new(stream: St, f: F) -> Self {
+        Self {
+            stream: Some(ManuallyDrop::new(stream)),
+            fut: None,
+            item: None,
+            f: Some(f),
+            done: false,
+        }
+    }
+}
+
+impl<St, Fut, F> AbortSafeStream for TakeWhile<St, Fut, F>
+where
+    St: AbortSafeStream,
+    Fut: AbortSafeFuture<Output = bool>,
+    F: FnMut(&St::Item) -> Fut,
+{
+    type Item = St::Item;
+
+    fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        loop {
+            let stream = match this.stream.as_mut().as_pin_mut() {
+                Some(stream) => stream,
+                None => panic!("TakeWhile::poll_next called after completion or after cancel"),
+            };
+
+            if *this.done {
+                // the source has not ended on its own, it must be cancelled
+                ready!(stream.poll_cancel(cx));
+                this.stream.set(None);
+                return Poll::Ready(None);
+            }
+
+            if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+                let keep = ready!(fut.poll(cx));
+                this.fut.set(None);
+                let item = this.item.take().unwrap();
+                if keep {
+                    return Poll::Ready(Some(item));
+                }
+
+                *this.done = true;
+                // drop closure
+                *this.f = None;
+                continue;
+            }
+
+            match ready!(stream.poll_next(cx)) {
+                Some(item) => {
+                    let fut = (this.f.as_mut().unwrap())(&item);
+                    *this.item = Some(item);
+                    this.fut.set(Some(ManuallyDrop::new(fut)));
+                }
+                None => {
+                    this.stream.set(None);
+                    // drop closure
+                    *this.f = None;
+                    return Poll::Ready(None);
+                }
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+            if fut.poll_cancel(cx).is_ready() {
+                this.fut.set(None);
+            }
+        }
+
+        if let Some(stream) = this.stream.as_mut().as_pin_mut() {
+            if stream.poll_cancel(cx).is_ready() {
+                this.stream.set(None);
+            }
+        }
+
+        if this.fut.is_some() || this.stream.is_some() {
+            return Poll::Pending;
+        }
+
+        // drop the pending item and closure
+        *this.item = None;
+        *this.f = None;
+        Poll::Ready(())
+    }
+}
+
+
+/// 跳过元素直到`f`第一次返回`false`，之后的元素全部产生，不再调用`f`
+///
+/// ```
+/// use abort_safe_future::AbortSafeStreamExt;
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::Compat;
+///
+/// let stream = Compat::new(futures::stream::iter(vec![1, 2, 3, 4, 1]))
+///     .skip_while(|x: &i32| abort_safe_future::ready(*x < 3));
+/// let items: Vec<_> = block_on(stream.collect());
+/// assert_eq!(items, [3, 4, 1]);
+/// ```
+#[pin_project]
+pub struct SkipWhile<St: AbortSafeStream, Fut, F> {
+    #[pin]
+    stream: Option<ManuallyDrop<St>>,
+    #[pin]
+    fut: Option<ManuallyDrop<Fut>>,
+    item: Option<St::Item>,
+    f: Option<F>,
+}
+
+impl<St: AbortSafeStream, Fut, F> SkipWhile<St, Fut, F> {
+    pub fn new(stream: St, f: F) -> Self {
+        Self {
+            stream: Some(ManuallyDrop::new(stream)),
+            fut: None,
+            item: None,
+            f: Some(f),
+        }
+    }
+}
+
+impl<St, Fut, F> AbortSafeStream for SkipWhile<St, Fut, F>
+where
+    St: AbortSafeStream,
+    Fut: AbortSafeFuture<Output = bool>,
+    F: FnMut(&St::Item) -> Fut,
+{
+    type Item = St::Item;
+
+    fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        loop {
+            if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+                let skip = ready!(fut.poll(cx));
+                this.fut.set(None);
+                let item = this.item.take().unwrap();
+                if !skip {
+                    // drop closure, the rest of the items are passed through
+                    *this.f = None;
+                    return Poll::Ready(Some(item));
+                }
+            }
+
+            let stream = match this.stream.as_mut().as_pin_mut() {
+                Some(stream) => stream,
+                None => panic!("SkipWhile::poll_next called after completion or after cancel"),
+            };
+
+            match ready!(stream.poll_next(cx)) {
+                Some(item) => match this.f.as_mut() {
+                    Some(f) => {
+                        let fut = f(&item);
+                        *this.item = Some(item);
+                        this.fut.set(Some(ManuallyDrop::new(fut)));
+                    }
+                    None => return Poll::Ready(Some(item)),
+                },
+                None => {
+                    this.stream.set(None);
+                    // drop closure
+                    *this.f = None;
+                    return Poll::Ready(None);
+                }
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+            if fut.poll_cancel(cx).is_ready() {
+                this.fut.set(None);
+            }
+        }
+
+        if let Some(stream) = this.stream.as_mut().as_pin_mut() {
+            if stream.poll_cancel(cx).is_ready() {
+                this.stream.set(None);
+            }
+        }
+
+        if this.fut.is_some() || this.stream.is_some() {
+            return Poll::Pending;
+        }
+
+        // drop the pending item and closure
+        *this.item = None;
+        *this.f = None;
+        Poll::Ready(())
+    }
+}
+
+
+/// 依次用`F`把每一个元素合并到累加值中，返回最终的累加值
+///
+/// ```
+/// use abort_safe_future::{ready, AbortSafeStreamExt};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::Compat;
+///
+/// let sum = Compat::new(futures::stream::iter(1..=4)).fold(0, |acc, x| ready(acc + x));
+/// assert_eq!(block_on(sum), 10);
+/// ```
+#[pin_project]
+pub struct Fold<St, Fut, F, Acc> {
+    #[pin]
+    stream: Option<ManuallyDrop<St>>,
+    #[pin]
+    fut: Option<ManuallyDrop<Fut>>,
+    acc: Option<Acc>,
+    f: Option<F>,
+}
+
+impl<St, Fut, F, Acc> Fold<St, Fut, F, Acc> {
+    pub fn new(stream: St, init: Acc, f: F) -> Self {
+        Self {
+            stream: Some(ManuallyDrop::new(stream)),
+            fut: None,
+            acc: Some(init),
+            f: Some(f),
+        }
+    }
+}
+
+impl<St, Fut, F, Acc> AbortSafeFuture for Fold<St, Fut, F, Acc>
+where
+    St: AbortSafeStream,
+    Fut: AbortSafeFuture<Output = Acc>,
+    F: FnMut(Acc, St::Item) -> Fut,
+{
+    type Output = Acc;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        loop {
+            if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+                let acc = ready!(fut.poll(cx));
+                this.fut.set(None);
+                *this.acc = Some(acc);
+            }
+
+            let stream = match this.stream.as_mut().as_pin_mut() {
+                Some(stream) => stream,
+                None => panic!("Fold::poll called after completion or after cancel"),
+            };
+
+            match ready!(stream.poll_next(cx)) {
+                Some(item) => {
+                    let acc = this.acc.take().unwrap();
+                    let fut = (this.f.as_mut().unwrap())(acc, item);
+                    this.fut.set(Some(ManuallyDrop::new(fut)));
+                }
+                None => {
+                    this.stream.set(None);
+                    // drop closure
+                    *this.f = None;
+                    return Poll::Ready(this.acc.take().unwrap());
+                }
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+            if fut.poll_cancel(cx).is_ready() {
+                this.fut.set(None);
+            }
+        }
+
+        if let Some(stream) = this.stream.as_mut().as_pin_mut() {
+            if stream.poll_cancel(cx).is_ready() {
+                this.stream.set(None);
+            }
+        }
+
+        if this.fut.is_some() || this.stream.is_some() {
+            return Poll::Pending;
+        }
+
+        // drop accumulator and closure
+        *this.acc = None;
+        *this.f = None;
+        Poll::Ready(())
+    }
+}
+
+
+/// 最多产生`n`个元素。达到上限之后，先取消内部的stream，再返回`Poll::Ready(None)`
+///
+/// ```
+/// use std::rc::Rc;
+/// use futures::StreamExt;
+/// use abort_safe_future::AbortSafeStreamExt as _;
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::Compat;
+///
+/// let resource = Rc::new(());
+/// let held = resource.clone();
+/// let source = futures::stream::iter(1..=5).map(move |x| {
+///     let _ = &held;
+///     x
+/// });
+/// let items: Vec<_> = block_on(Compat::new(source).take(2).collect());
+/// assert_eq!(items, [1, 2]);
+/// // the source was cancelled, not left half consumed
+/// assert_eq!(Rc::strong_count(&resource), 1);
+/// ```
+#[pin_project]
+pub struct Take<St> {
+    #[pin]
+    stream: Option<ManuallyDrop<St>>,
+    remaining: usize,
+}
+
+impl<St> Take<St> {
+    pub fn new(stream: St, n: usize) -> Self {
+        Self {
+            stream: Some(ManuallyDrop::new(stream)),
+            remaining: n,
+        }
+    }
+}
+
+impl<St: AbortSafeStream> AbortSafeStream for Take<St> {
+    type Item = St::Item;
+
+    fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        let stream = match this.stream.as_mut().as_pin_mut() {
+            Some(stream) => stream,
+            None => panic!("Take::poll_next called after completion or after cancel"),
+        };
+
+        if *this.remaining == 0 {
+            // the source has not ended on its own, it must be cancelled
+            ready!(stream.poll_cancel(cx));
+            this.stream.set(None);
+            return Poll::Ready(None);
+        }
+
+        match ready!(stream.poll_next(cx)) {
+            Some(item) => {
+                *this.remaining -= 1;
+                Poll::Ready(Some(item))
+            }
+            None => {
+                this.stream.set(None);
+                Poll::Ready(None)
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(stream) = this.stream.as_mut().as_pin_mut() {
+            ready!(stream.poll_cancel(cx));
+            this.stream.set(None);
+        }
+
+        Poll::Ready(())
+    }
+}
+
+
+/// 产生`(index, item)`，`index`从0开始
+///
+/// ```
+/// use abort_safe_future::AbortSafeStreamExt;
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::Compat;
+///
+/// let stream = Compat::new(futures::stream::iter(vec!['a', 'b', 'c'])).enumerate();
+/// let items: Vec<_> = block_on(stream.collect());
+/// assert_eq!(items, [(0, 'a'), (1, 'b'), (2, 'c')]);
+/// ```
+#[pin_project]
+pub struct Enumerate<St> {
+    #[pin]
+    stream: Option<ManuallyDrop<St>>,
+    count: usize,
+}
+
+impl<St> Enumerate<St> {
+    pub fn new(stream: St) -> Self {
+        Self {
+            stream: Some(ManuallyDrop::new(stream)),
+            count: 0,
+        }
+    }
+}
+
+impl<St: AbortSafeStream> AbortSafeStream for Enumerate<St> {
+    type Item = (usize, St::Item);
+
+    fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        let stream = match this.stream.as_mut().as_pin_mut() {
+            Some(stream) => stream,
+            None => panic!("Enumerate::poll_next called after completion or after cancel"),
+        };
+
+        match ready!(stream.poll_next(cx)) {
+            Some(item) => {
+                let index = *this.count;
+                *this.count += 1;
+                Poll::Ready(Some((index, item)))
+            }
+            None => {
+                this.stream.set(None);
+                Poll::Ready(None)
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(stream) = this.stream.as_mut().as_pin_mut() {
+            ready!(stream.poll_cancel(cx));
+            this.stream.set(None);
+        }
+
+        Poll::Ready(())
+    }
+}
+
+
+/// 每次从两边各取一个元素。任意一边结束时，先取消另一边，再结束
+///
+/// ```
+/// use std::rc::Rc;
+/// use futures::StreamExt;
+/// use abort_safe_future::AbortSafeStreamExt as _;
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::Compat;
+///
+/// let resource = Rc::new(());
+/// let held = resource.clone();
+/// let longer = futures::stream::iter(vec!['a', 'b', 'c', 'd', 'e']).map(move |c| {
+///     let _ = &held;
+///     c
+/// });
+/// let zipped = Compat::new(futures::stream::iter(1..=3)).zip(Compat::new(longer));
+/// let items: Vec<_> = block_on(zipped.collect());
+/// assert_eq!(items, [(1, 'a'), (2, 'b'), (3, 'c')]);
+/// // the longer stream was cancelled with two items left
+/// assert_eq!(Rc::strong_count(&resource), 1);
+/// ```
+#[pin_project]
+pub struct Zip<St1: AbortSafeStream, St2: AbortSafeStream> {
+    #[pin]
+    stream1: Option<ManuallyDrop<St1>>,
+    #[pin]
+    stream2: Option<ManuallyDrop<St2>>,
+    item1: Option<St1::Item>,
+    item2: Option<St2::Item>,
+    ended: bool,
+}
+
+impl<St1: AbortSafeStream, St2: AbortSafeStream> Zip<St1, St2> {
+    pub fn new(stream1: St1, stream2: St2) -> Self {
+        Self {
+            stream1: Some(ManuallyDrop::new(stream1)),
+            stream2: Some(ManuallyDrop::new(stream2)),
+            item1: None,
+            item2: None,
+            ended: false,
+        }
+    }
+}
+
+impl<St1, St2> AbortSafeStream for Zip<St1, St2>
+where
+    St1: AbortSafeStream,
+    St2: AbortSafeStream,
+{
+    type Item = (St1::Item, St2::Item);
+
+    fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if this.stream1.is_none() && this.stream2.is_none() {
+            panic!("Zip::poll_next called after completion or after cancel")
+        }
+
+        if !*this.ended && this.item1.is_none() {
+            if let Poll::Ready(item) = this.stream1.as_mut().as_pin_mut().unwrap().poll_next(cx) {
+                match item {
+                    Some(item) => *this.item1 = Some(item),
+                    None => {
+                        this.stream1.set(None);
+                        *this.ended = true;
+                    }
+                }
+            }
+        }
+
+        if !*this.ended && this.item2.is_none() {
+            if let Poll::Ready(item) = this.stream2.as_mut().as_pin_mut().unwrap().poll_next(cx) {
+                match item {
+                    Some(item) => *this.item2 = Some(item),
+                    None => {
+                        this.stream2.set(None);
+                        *this.ended = true;
+                    }
+                }
+            }
+        }
+
+        if *this.ended {
+            // one side has ended, the other one must be cancelled before ending
+            if let Some(stream1) = this.stream1.as_mut().as_pin_mut() {
+                ready!(stream1.poll_cancel(cx));
+                this.stream1.set(None);
+            }
+            if let Some(stream2) = this.stream2.as_mut().as_pin_mut() {
+                ready!(stream2.poll_cancel(cx));
+                this.stream2.set(None);
+            }
+
+            // drop the unpaired item
+            *this.item1 = None;
+            *this.item2 = None;
+            return Poll::Ready(None);
+        }
+
+        if this.item1.is_some() && this.item2.is_some() {
+            return Poll::Ready(Some((this.item1.take().unwrap(), this.item2.take().unwrap())));
+        }
+
+        Poll::Pending
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        if let Some(stream1) = this.stream1.as_mut().as_pin_mut() {
+            if stream1.poll_cancel(cx).is_ready() {
+                this.stream1.set(None);
+            }
+        }
+
+        if let Some(stream2) = this.stream2.as_mut().as_pin_mut() {
+            if stream2.poll_cancel(cx).is_ready() {
+                this.stream2.set(None);
+            }
+        }
+
+        if this.stream1.is_some() || this.stream2.is_some() {
+            return Poll::Pending;
+        }
+
+        // drop buffered items
+        *this.item1 = None;
+        *this.item2 = None;
+        Poll::Ready(())
+    }
+}
+
+
+/// 先产生第一个stream的所有元素，再产生第二个stream的所有元素
+///
+/// ```
+/// use abort_safe_future::AbortSafeStreamExt;
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::Compat;
+///
+/// let chained = Compat::new(futures::stream::iter(vec![1, 2])).chain(Compat::new(futures::stream::iter(vec![3, 4])));
+/// let items: Vec<_> = block_on(chained.collect());
+/// assert_eq!(items, [1, 2, 3, 4]);
+/// ```
+///
+/// 在第二个stream中途取消时，第二个stream会被取消：
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use std::rc::Rc;
+/// use futures::StreamExt;
+/// use abort_safe_future::AbortSafeStreamExt as _;
+/// use abort_safe_future::stream::Compat;
+/// use abort_safe_future::testing::{poll_cancel_once, poll_once};
+///
+/// let resource = Rc::new(());
+/// let held = resource.clone();
+/// let second = futures::stream::iter(vec![3])
+///     .chain(futures::stream::pending())
+///     .map(move |x| {
+///         let _ = &held;
+///         x
+///     });
+/// let chained = Compat::new(futures::stream::iter(vec![1, 2])).chain(Compat::new(second));
+/// let mut fut = Box::pin(ManuallyDrop::new(chained.collect::<Vec<_>>()));
+/// assert!(poll_once(fut.as_mut()).is_pending());
+/// assert!(poll_cancel_once(fut.as_mut()).is_ready());
+/// assert_eq!(Rc::strong_count(&resource), 1);
+/// ```
+#[pin_project]
+pub struct Chain<St1, St2> {
+    #[pin]
+    first: Option<ManuallyDrop<St1>>,
+    #[pin]
+    second: Option<ManuallyDrop<St2>>,
+}
+
+impl<St1, St2> Chain<St1, St2> {
+    pub fn new(first: St1, second: St2) -> Self {
+        Self {
+            first: Some(ManuallyDrop::new(first)),
+            second: Some(ManuallyDrop::new(second)),
+        }
+    }
+}
+
+impl<St1, St2> AbortSafeStream for Chain<St1, St2>
+where
+    St1: AbortSafeStream,
+    St2: AbortSafeStream<Item = St1::Item>,
+{
+    type Item = St1::Item;
+
+    fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        if let Some(first) = this.first.as_mut().as_pin_mut() {
+            match ready!(first.poll_next(cx)) {
+                Some(item) => return Poll::Ready(Some(item)),
+                // the first stream has reclaimed its resources when it ends
+                None => this.first.set(None),
+            }
+        }
+
+        let second = match this.second.as_mut().as_pin_mut() {
+            Some(second) => second,
+            None => panic!("Chain::poll_next called after completion or after cancel"),
+        };
+
+        let item = ready!(second.poll_next(cx));
+        if item.is_none() {
+            this.second.set(None);
+        }
+        Poll::Ready(item)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        // the second stream is owned even if it has not started yet
+        if let Some(first) = this.first.as_mut().as_pin_mut() {
+            if first.poll_cancel(cx).is_ready() {
+                this.first.set(None);
+            }
+        }
+
+        if let Some(second) = this.second.as_mut().as_pin_mut() {
+            if second.poll_cancel(cx).is_ready() {
+                this.second.set(None);
+            }
+        }
+
+        if this.first.is_some() || this.second.is_some() {
+            return Poll::Pending;
+        }
+
+        Poll::Ready(())
+    }
+}
+
+
+/// 与`ForEach`类似，但是`f`返回的future出错时，先取消stream，再返回这个错误
+///
+/// ```
+/// use std::cell::Cell;
+/// use std::rc::Rc;
+/// use futures::StreamExt;
+/// use abort_safe_future::{ready, AbortSafeStreamExt as _};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::Compat;
+///
+/// let resource = Rc::new(());
+/// let held = resource.clone();
+/// let source = futures::stream::iter(1..=4).map(move |x| {
+///     let _ = &held;
+///     x
+/// });
+/// let calls = Cell::new(0);
+/// let fut = Compat::new(source).try_for_each(|x| {
+///     calls.set(calls.get() + 1);
+///     ready(if x == 2 { Err(x) } else { Ok(()) })
+/// });
+/// assert_eq!(block_on(fut), Err(2));
+/// assert_eq!(calls.get(), 2);
+/// assert_eq!(Rc::strong_count(&resource), 1);
+/// ```
+#[pin_project]
+pub struct TryForEach<St, Fut: AbortSafeFuture, F> {
+    #[pin]
+    stream: Option<ManuallyDrop<St>>,
+    #[pin]
+    fut: Option<ManuallyDrop<Fut>>,
+    error: Option<Fut::Output>,
+    f: Option<F>,
+}
+
+impl<St, Fut: AbortSafeFuture, F> TryForEach<St, Fut, F> {
+    pub fn new(stream: St, f: F) -> Self {
+        Self {
+            stream: Some(ManuallyDrop::new(stream)),
+            fut: None,
+            error: None,
+            f: Some(f),
+        }
+    }
+}
+
+impl<St, Fut, F, E> AbortSafeFuture for TryForEach<St, Fut, F>
+where
+    St: AbortSafeStream,
+    Fut: AbortSafeFuture<Output = Result<(), E>>,
+    F: FnMut(St::Item) -> Fut,
+{
+    type Output = Result<(), E>;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        loop {
+            if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+                let output = ready!(fut.poll(cx));
+                this.fut.set(None);
+                if output.is_err() {
+                    *this.error = Some(output);
+                    // drop closure
+                    *this.f = None;
+                }
+            }
+
+            let stream = match this.stream.as_mut().as_pin_mut() {
+                Some(stream) => stream,
+                None => panic!("TryForEach::poll called after completion or after cancel"),
+            };
+
+            if this.error.is_some() {
+                // the stream must be cancelled before reporting the error
+                ready!(stream.poll_cancel(cx));
+                this.stream.set(None);
+                return Poll::Ready(this.error.take().unwrap());
+            }
+
+            match ready!(stream.poll_next(cx)) {
+                Some(item) => {
+                    let fut = (this.f.as_mut().unwrap())(item);
+                    this.fut.set(Some(ManuallyDrop::new(fut)));
+                }
+                None => {
+                    this.stream.set(None);
+                    // drop closure
+                    *this.f = None;
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+            if fut.poll_cancel(cx).is_ready() {
+                this.fut.set(None);
+            }
+        }
+
+        if let Some(stream) = this.stream.as_mut().as_pin_mut() {
+            if stream.poll_cancel(cx).is_ready() {
+                this.stream.set(None);
+            }
+        }
+
+        if this.fut.is_some() || this.stream.is_some() {
+            return Poll::Pending;
+        }
+
+        // drop error and closure
+        *this.error = None;
+        *this.f = None;
+        Poll::Ready(())
+    }
+}
+
+
+/// 可以在不取出元素的情况下查看下一个元素。最多缓存一个元素，取消时直接析构缓存的元素
+#[pin_project]
+pub struct Peekable<St: AbortSafeStream> {
+    #[pin]
+    stream: Option<ManuallyDrop<St>>,
+    // `Some(None)` means `peek` has seen the end of the stream
+    peeked: Option<Option<St::Item>>,
+}
+
+impl<St: AbortSafeStream> Peekable<St> {
+    pub fn new(stream: St) -> Self {
+        Self {
+            stream: Some(ManuallyDrop::new(stream)),
+            peeked: None,
+        }
+    }
+
+    /// 查看下一个元素。`Peek`只借用了stream，取消`Peek`不会丢失元素
+    ///
+    /// ```
+    /// #![feature(arbitrary_self_types)]
+    /// use std::mem::ManuallyDrop;
+    /// use abort_safe_future::AbortSafeStreamExt;
+    /// use abort_safe_future::executor::block_on;
+    /// use abort_safe_future::stream::Compat;
+    ///
+    /// let stream = Compat::new(futures::stream::iter(vec![1, 2])).peekable();
+    /// let mut stream = Box::pin(ManuallyDrop::new(stream));
+    ///
+    /// assert_eq!(block_on(stream.as_mut().peek()), Some(&1));
+    /// assert_eq!(block_on(stream.as_mut().next()), Some(1));
+    /// assert_eq!(block_on(stream.as_mut().next()), Some(2));
+    /// assert_eq!(block_on(stream.as_mut().peek()), None);
+    /// assert_eq!(block_on(stream.as_mut().next()), None);
+    /// ```
+    pub fn peek<'a>(self: Pin<&'a mut ManuallyDrop<Self>>) -> Peek<'a, St> {
+        Peek::new(self)
+    }
+
+    fn poll_peek(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if this.peeked.is_some() {
+            return Poll::Ready(());
+        }
+
+        let stream = match this.stream.as_mut().as_pin_mut() {
+            Some(stream) => stream,
+            None => panic!("Peekable::peek called after completion or after cancel"),
+        };
+
+        let item = ready!(stream.poll_next(cx));
+        if item.is_none() {
+            this.stream.set(None);
+        }
+        *this.peeked = Some(item);
+        Poll::Ready(())
+    }
+}
+
+impl<St: AbortSafeStream> AbortSafeStream for Peekable<St> {
+    type Item = St::Item;
+
+    fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(item) = this.peeked.take() {
+            return Poll::Ready(item);
+        }
+
+        let stream = match this.stream.as_mut().as_pin_mut() {
+            Some(stream) => stream,
+            None => panic!("Peekable::poll_next called after completion or after cancel"),
+        };
+
+        let item = ready!(stream.poll_next(cx));
+        if item.is_none() {
+            this.stream.set(None);
+        }
+        Poll::Ready(item)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(stream) = this.stream.as_mut().as_pin_mut() {
+            ready!(stream.poll_cancel(cx));
+            this.stream.set(None);
+        }
+
+        // drop the buffered item
+        *this.peeked = None;
+        Poll::Ready(())
+    }
+}
+
+pub struct Peek<'a, St: AbortSafeStream> {
+    peekable: Option<Pin<&'a mut ManuallyDrop<Peekable<St>>>>,
+}
+
+impl<'a, St: AbortSafeStream> Peek<'a, St> {
+    pub fn new(peekable: Pin<&'a mut ManuallyDrop<Peekable<St>>>) -> Self {
+        Self { peekable: Some(peekable) }
+    }
+}
+
+impl<'a, St: AbortSafeStream> AbortSafeFuture for Peek<'a, St> {
+    type Output = Option<&'a St::Item>;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let peekable = match &mut self.peekable {
+            Some(peekable) => peekable,
+            None => panic!("Peek::poll called after completion or after cancel"),
+        };
+
+        ready!(peekable.as_mut().poll_peek(cx));
+        let peekable = self.peekable.take().unwrap().into_ref().get_ref();
+        Poll::Ready(peekable.peeked.as_ref().unwrap().as_ref())
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+        // the buffered item belongs to the `Peekable`, only release the borrow
+        self.peekable = None;
+        Poll::Ready(())
+    }
+}
+
+/// 把stream分成两半，每一半都会收到所有元素的克隆。
+///
+/// 两半共享一个缓冲区，较快的一半取出的元素会保存到较慢的一半也取出为止。
+/// 两半都结束或者被取消之后，源stream才会被取消，且只会被取消一次。
+///
+/// ```
+/// use abort_safe_future::{join, AbortSafeStreamExt};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::{split, Compat};
+///
+/// let (left, right) = split(Compat::new(futures::stream::iter(vec![1, 2, 3])));
+/// let (left, right): (Vec<_>, Vec<_>) = block_on(join(left.collect(), right.collect()));
+/// assert_eq!(left, [1, 2, 3]);
+/// assert_eq!(right, [1, 2, 3]);
+/// ```
+///
+/// 提前取消一半时，源stream要等另一半也被取消才会被取消：
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::cell::Cell;
+/// use std::mem::ManuallyDrop;
+/// use std::pin::Pin;
+/// use std::rc::Rc;
+/// use std::task::{Context, Poll};
+/// use abort_safe_future::{AbortSafeStream, AbortSafeStreamExt};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::split;
+/// use abort_safe_future::testing::noop_waker;
+///
+/// // yields 1 and 2, then never ends
+/// struct Source {
+///     next: u32,
+///     cancels: Rc<Cell<u32>>,
+/// }
+///
+/// impl AbortSafeStream for Source {
+///     type Item = u32;
+///
+///     fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<Option<u32>> {
+///         if self.next > 2 {
+///             return Poll::Pending;
+///         }
+///         self.next += 1;
+///         Poll::Ready(Some(self.next - 1))
+///     }
+///
+///     fn poll_cancel(self: Pin<&mut ManuallyDrop<Self>>, _cx: &mut Context<'_>) -> Poll<()> {
+///         self.cancels.set(self.cancels.get() + 1);
+///         Poll::Ready(())
+///     }
+/// }
+///
+/// let cancels = Rc::new(Cell::new(0));
+/// let (left, right) = split(Source { next: 1, cancels: cancels.clone() });
+/// let mut left = Box::pin(ManuallyDrop::new(left));
+/// let mut right = Box::pin(ManuallyDrop::new(right));
+/// let waker = noop_waker();
+/// let mut cx = Context::from_waker(&waker);
+///
+/// assert!(left.as_mut().poll_cancel(&mut cx).is_ready());
+/// assert_eq!(cancels.get(), 0);
+///
+/// assert_eq!(block_on(right.as_mut().next()), Some(1));
+/// assert_eq!(block_on(right.as_mut().next()), Some(2));
+/// assert!(right.as_mut().poll_next(&mut cx).is_pending());
+/// assert_eq!(cancels.get(), 0);
+///
+/// assert!(right.as_mut().poll_cancel(&mut cx).is_ready());
+/// assert!(right.as_mut().poll_cancel(&mut cx).is_ready());
+/// assert!(left.as_mut().poll_cancel(&mut cx).is_ready());
+/// assert_eq!(cancels.get(), 1);
+/// ```
+pub fn split<St>(stream: St) -> (SplitHalf<St>, SplitHalf<St>)
+where
+    St: AbortSafeStream,
+    St::Item: Clone,
+{
+    let shared = Arc::new(Mutex::new(SplitShared {
+        stream: Some(Box::pin(ManuallyDrop::new(stream))),
+        buffer: VecDeque::new(),
+        offset: 0,
+        cursors: [0, 0],
+        alive: [true, true],
+        wakers: Arc::new(SplitWaker {
+            wakers: Mutex::new([None, None]),
+        }),
+    }));
+
+    let left = SplitHalf {
+        shared: Some(shared.clone()),
+        index: 0,
+    };
+    let right = SplitHalf {
+        shared: Some(shared),
+        index: 1,
+    };
+    (left, right)
+}
+
+struct SplitShared<St: AbortSafeStream> {
+    // `None` once the source has ended or been cancelled
+    stream: Option<Pin<Box<ManuallyDrop<St>>>>,
+    buffer: VecDeque<St::Item>,
+    // position of `buffer[0]` in the source
+    offset: usize,
+    cursors: [usize; 2],
+    alive: [bool; 2],
+    wakers: Arc<SplitWaker>,
+}
+
+impl<St: AbortSafeStream> SplitShared<St> {
+    /// 丢掉所有还活着的一半都已经取出的元素
+    fn trim(&mut self) {
+        let min = (0..2).filter(|&i| self.alive[i]).map(|i| self.cursors[i]).min();
+        match min {
+            Some(min) => {
+                while self.offset < min {
+                    self.buffer.pop_front();
+                    self.offset += 1;
+                }
+            }
+            None => self.buffer.clear(),
+        }
+    }
+}
+
+/// 源stream唤醒时，两半都要被唤醒
+struct SplitWaker {
+    wakers: Mutex<[Option<Waker>; 2]>,
+}
+
+impl SplitWaker {
+    fn wake_half(&self, index: usize) {
+        if let Some(waker) = self.wakers.lock().unwrap()[index].take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Wake for SplitWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_half(0);
+        self.wake_half(1);
+    }
+}
+
+/// `split`返回的一半
+pub struct SplitHalf<St: AbortSafeStream> {
+    // released once this half has ended or been cancelled
+    shared: Option<Arc<Mutex<SplitShared<St>>>>,
+    index: usize,
+}
+
+impl<St: AbortSafeStream> Unpin for SplitHalf<St> {}
+
+impl<St> AbortSafeStream for SplitHalf<St>
+where
+    St: AbortSafeStream,
+    St::Item: Clone,
+{
+    type Item = St::Item;
+
+    fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = pin_manually_drop_as_mut(&mut self).get_mut();
+        let index = this.index;
+        let mut guard = match &this.shared {
+            Some(shared) => shared.lock().unwrap(),
+            None => panic!("SplitHalf::poll_next called after completion or after cancel"),
+        };
+        let shared = &mut *guard;
+
+        let cursor = shared.cursors[index];
+        if cursor < shared.offset + shared.buffer.len() {
+            let item = shared.buffer[cursor - shared.offset].clone();
+            shared.cursors[index] += 1;
+            shared.trim();
+            return Poll::Ready(Some(item));
+        }
+
+        let stream = match &mut shared.stream {
+            Some(stream) => stream,
+            None => {
+                // the source has ended
+                shared.alive[index] = false;
+                shared.trim();
+                drop(guard);
+                this.shared = None;
+                return Poll::Ready(None);
+            }
+        };
+
+        shared.wakers.wakers.lock().unwrap()[index] = Some(cx.waker().clone());
+        let waker = Waker::from(shared.wakers.clone());
+        let polled = stream.as_mut().poll_next(&mut Context::from_waker(&waker));
+        match polled {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Some(item)) => {
+                shared.buffer.push_back(item.clone());
+                shared.cursors[index] += 1;
+                shared.trim();
+                // the other half may be waiting for this item
+                shared.wakers.wake_half(1 - index);
+                Poll::Ready(Some(item))
+            }
+            Poll::Ready(None) => {
+                shared.stream = None;
+                shared.alive[index] = false;
+                shared.trim();
+                shared.wakers.wake_half(1 - index);
+                drop(guard);
+                this.shared = None;
+                Poll::Ready(None)
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).get_mut();
+        let mut shared = match &this.shared {
+            Some(shared) => shared.lock().unwrap(),
+            None => return Poll::Ready(()),
+        };
+        shared.alive[this.index] = false;
+        shared.trim();
+
+        // only the last half cancels the source
+        if !shared.alive.iter().any(|&alive| alive) {
+            if let Some(stream) = &mut shared.stream {
+                ready!(stream.as_mut().poll_cancel(cx));
+                shared.stream = None;
+            }
+        }
+
+        drop(shared);
+        this.shared = None;
+        Poll::Ready(())
+    }
+}
+
+
+/// 一组abort safe future，按照完成的顺序产生它们的结果。
+///
+/// 只有被唤醒的future才会被再次`poll`。返回`Poll::Ready(None)`表示当前已经没有future了，
+/// 这时内部的缓冲区已经被释放，之后仍然可以`push`新的future继续使用。
+/// 取消时会对所有还没有完成的future调用`poll_cancel`。
+///
+/// ```
+/// use std::task::Poll;
+/// use abort_safe_future::{poll_fn, AbortSafeStreamExt};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::AbortSafeUnordered;
+/// use abort_safe_future::testing::checked;
+///
+/// let mut set = AbortSafeUnordered::new();
+/// let mut checks = Vec::new();
+/// for n in [4, 0, 3, 1, 2] {
+///     let mut left = n;
+///     let (fut, finalized) = checked(poll_fn(move |cx| {
+///         if left == 0 {
+///             return Poll::Ready(n);
+///         }
+///         left -= 1;
+///         cx.waker().wake_by_ref();
+///         Poll::Pending
+///     }));
+///     set.push(fut);
+///     checks.push(finalized);
+/// }
+///
+/// let outputs: Vec<_> = block_on(set.collect());
+/// assert_eq!(outputs, [0, 1, 2, 3, 4]);
+/// assert!(checks.iter().all(|finalized| finalized.is_finalized()));
+/// ```
+pub struct AbortSafeUnordered<Fut> {
+    slots: Vec<Option<UnorderedSlot<Fut>>>,
+    free: Vec<usize>,
+    queue: Option<Arc<ReadyQueue>>,
+}
+
+struct UnorderedSlot<Fut> {
+    fut: Pin<Box<ManuallyDrop<Fut>>>,
+    waker: Arc<SlotWaker>,
+}
+
+/// indices of the woken slots, plus the waker of the task polling the set
+struct ReadyQueue {
+    indices: Mutex<VecDeque<usize>>,
+    parent: Mutex<Option<Waker>>,
+}
+
+struct SlotWaker {
+    index: usize,
+    queued: AtomicBool,
+    queue: Arc<ReadyQueue>,
+}
+
+impl Wake for SlotWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.queue.indices.lock().unwrap().push_back(self.index);
+            if let Some(parent) = &*self.queue.parent.lock().unwrap() {
+                parent.wake_by_ref();
+            }
+        }
+    }
+}
+
+impl<Fut> AbortSafeUnordered<Fut> {
+    pub fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            free: Vec::new(),
+            queue: None,
+        }
+    }
+
+    /// 还没有完成的future的数量
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(&mut self, fut: Fut) {
+        let index = self.free.pop().unwrap_or(self.slots.len());
+        let queue = self.queue.get_or_insert_with(|| {
+            Arc::new(ReadyQueue {
+                indices: Mutex::new(VecDeque::new()),
+                parent: Mutex::new(None),
+            })
+        });
+        let waker = Arc::new(SlotWaker {
+            index,
+            queued: AtomicBool::new(false),
+            queue: queue.clone(),
+        });
+        let slot = UnorderedSlot {
+            fut: Box::pin(ManuallyDrop::new(fut)),
+            waker: waker.clone(),
+        };
+
+        if index == self.slots.len() {
+            self.slots.push(Some(slot));
+        } else {
+            self.slots[index] = Some(slot);
+        }
+
+        // newly pushed futures have to be polled once
+        waker.wake_by_ref();
+    }
+
+    /// release the buffers and the queue once there is no future left
+    fn release(&mut self) {
+        self.slots = Vec::new();
+        self.free = Vec::new();
+        self.queue = None;
+    }
+}
+
+impl<Fut> Default for AbortSafeUnordered<Fut> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Fut> FromIterator<Fut> for AbortSafeUnordered<Fut> {
+    fn from_iter<I: IntoIterator<Item = Fut>>(iter: I) -> Self {
+        let mut set = Self::new();
+        for fut in iter {
+            set.push(fut);
+        }
+        set
+    }
+}
+
+impl<Fut: AbortSafeFuture> AbortSafeStream for AbortSafeUnordered<Fut> {
+    type Item = Fut::Output;
+
+    fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = pin_manually_drop_as_mut(&mut self).get_mut();
+        if this.is_empty() {
+            this.release();
+            return Poll::Ready(None);
+        }
+
+        let queue = this.queue.clone().unwrap();
+        *queue.parent.lock().unwrap() = Some(cx.waker().clone());
+
+        // only poll the slots woken before this call, so a self-waking future can't starve the caller
+        let woken = queue.indices.lock().unwrap().len();
+        for _ in 0..woken {
+            let index = match queue.indices.lock().unwrap().pop_front() {
+                Some(index) => index,
+                None => break,
+            };
+
+            // the slot may have completed and been reused since it was woken
+            let slot = match this.slots.get_mut(index) {
+                Some(Some(slot)) => slot,
+                _ => continue,
+            };
+
+            slot.waker.queued.store(false, Ordering::Release);
+            let waker = Waker::from(slot.waker.clone());
+            if let Poll::Ready(output) = slot.fut.as_mut().poll(&mut Context::from_waker(&waker)) {
+                // the future has reclaimed its resources, release the box
+                this.slots[index] = None;
+                this.free.push(index);
+                return Poll::Ready(Some(output));
+            }
+        }
+
+        Poll::Pending
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).get_mut();
+
+        let mut all_canceled = true;
+        for slot in this.slots.iter_mut() {
+            if let Some(s) = slot {
+                if s.fut.as_mut().poll_cancel(cx).is_ready() {
+                    *slot = None;
+                } else {
+                    all_canceled = false;
+                }
+            }
+        }
+
+        if !all_canceled {
+            return Poll::Pending;
+        }
+
+        this.release();
+        Poll::Ready(())
+    }
+}
+
+/// 元素是future的stream，最多同时执行`n`个，按照完成的顺序产生它们的结果
+///
+/// ```
+/// use std::cell::Cell;
+/// use std::task::Poll;
+/// use abort_safe_future::{poll_fn, AbortSafeStreamExt};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::stream::Compat;
+///
+/// let running = Cell::new(0);
+/// let most = Cell::new(0);
+/// let futs = (0..4).map(|i| {
+///     let (running, most) = (&running, &most);
+///     let mut polls = 0;
+///     poll_fn(move |cx| {
+///         if polls == 0 {
+///             running.set(running.get() + 1);
+///             most.set(most.get().max(running.get()));
+///         }
+///         polls += 1;
+///         if polls <= 2 {
+///             cx.waker().wake_by_ref();
+///             return Poll::Pending;
+///         }
+///         running.set(running.get() - 1);
+///         Poll::Ready(i)
+///     })
+/// });
+///
+/// let stream = Compat::new(futures::stream::iter(futs)).buffer_unordered(2);
+/// let mut out: Vec<i32> = block_on(stream.collect());
+/// out.sort();
+/// assert_eq!(out, [0, 1, 2, 3]);
+/// assert_eq!(most.get(), 2);
+/// ```
+#[pin_project]
+pub struct BufferUnordered<St: AbortSafeStream> {
+    #[pin]
+    stream: Option<ManuallyDrop<St>>,
+    pool: ManuallyDrop<AbortSafeUnordered<St::Item>>,
+    n: usize,
+}
+
+impl<St: AbortSafeStream> BufferUnordered<St> {
+    pub fn new(stream: St, n: usize) -> Self {
+        assert!(n > 0, "buffer_unordered called with a limit of zero");
+        Self {
+            stream: Some(ManuallyDrop::new(stream)),
+            pool: ManuallyDrop::new(AbortSafeUnordered::new()),
+            n,
+        }
+    }
+}
+
+impl<St> AbortSafeStream for BufferUnordered<St>
+where
+    St: AbortSafeStream,
+    St::Item: AbortSafeFuture,
+{
+    type Item = <St::Item as AbortSafeFuture>::Output;
+
+    fn poll_next(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        while this.pool.len() < *this.n {
+            let stream = match this.stream.as_mut().as_pin_mut() {
+                Some(stream) => stream,
+                None => break,
+            };
+
+            match stream.poll_next(cx) {
+                Poll::Ready(Some(fut)) => this.pool.push(fut),
+                Poll::Ready(None) => this.stream.set(None),
+                Poll::Pending => break,
+            }
+        }
+
+        match Pin::new(&mut *this.pool).poll_next(cx) {
+            Poll::Ready(Some(output)) => Poll::Ready(Some(output)),
+            // the pool has released its buffers when it is empty
+            Poll::Ready(None) if this.stream.is_none() => Poll::Ready(None),
+            _ => Poll::Pending,
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        if let Some(stream) = this.stream.as_mut().as_pin_mut() {
+            if stream.poll_cancel(cx).is_ready() {
+                this.stream.set(None);
+            }
+        }
+
+        let pool_canceled = Pin::new(&mut *this.pool).poll_cancel(cx).is_ready();
+        if this.stream.is_some() || !pool_canceled {
+            return Poll::Pending;
+        }
+
+        Poll::Ready(())
+    }
+}
+
+
+pub trait AbortSafeStreamExt: AbortSafeStream {
+    fn next<'a>(self: Pin<&'a mut ManuallyDrop<Self>>) -> Next<'a, Self> {
+        Next::new(self)
+    }
+
+    fn for_each<Fut, F>(self, f: F) -> ForEach<Self, Fut, F>
+    where
+        Self: Sized,
+        Fut: AbortSafeFuture<Output = ()>,
+        F: FnMut(Self::Item) -> Fut,
+    {
+        ForEach::new(self, f)
+    }
+
+    fn collect<C>(self) -> Collect<Self, C>
+    where
+        Self: Sized,
+        C: Default + Extend<Self::Item>,
+    {
+        Collect::new(self)
+    }
+
+    fn map<T, F>(self, f: F) -> Map<Self, F>
+    where
+        Self: Sized,
+        F: FnMut(Self::Item) -> T,
+    {
+        Map::new(self, f)
+    }
+
+    fn filter<Fut, F>(self, f: F) -> Filter<Self, Fut, F>
+    where
+        Self: Sized,
+        Fut: AbortSafeFuture<Output = bool>,
+        F: FnMut(&Self::Item) -> Fut,
+    {
+        Filter::new(self, f)
+    }
+
+    fn fold<Acc, Fut, F>(self, init: Acc, f: F) -> Fold<Self, Fut, F, Acc>
+    where
+        Self: Sized,
+        Fut: AbortSafeFuture<Output = Acc>,
+        F: FnMut(Acc, Self::Item) -> Fut,
+    {
+        Fold::new(self, init, f)
+    }
+
+    fn take(self, n: usize) -> Take<Self>
+    where
+        Self: Sized,
+    {
+        Take::new(self, n)
+    }
+
+    fn zip<St>(self, other: St) -> Zip<Self, St>
+    where
+        Self: Sized,
+        St: AbortSafeStream,
+    {
+        Zip::new(self, other)
+    }
+
+    fn chain<St>(self, next: St) -> Chain<Self, St>
+    where
+        Self: Sized,
+        St: AbortSafeStream<Item = Self::Item>,
+    {
+        Chain::new(self, next)
+    }
+
+    fn try_for_each<E, Fut, F>(self, f: F) -> TryForEach<Self, Fut, F>
+    where
+        Self: Sized,
+        Fut: AbortSafeFuture<Output = Result<(), E>>,
+        F: FnMut(Self::Item) -> Fut,
+    {
+        TryForEach::new(self, f)
+    }
+
+    fn buffer_unordered(self, n: usize) -> BufferUnordered<Self>
+    where
+        Self: Sized,
+        Self::Item: AbortSafeFuture,
+    {
+        BufferUnordered::new(self, n)
+    }
+
+    fn peekable(self) -> Peekable<Self>
+    where
+        Self: Sized,
+    {
+        Peekable::new(self)
+    }
+
+    fn enumerate(self) -> Enumerate<Self>
+    where
+        Self: Sized,
+    {
+        Enumerate::new(self)
+    }
+
+    fn take_while<Fut, F>(self, f: F) -> TakeWhile<Self, Fut, F>
+    where
+        Self: Sized,
+        Fut: AbortSafeFuture<Output = bool>,
+        F: FnMut(&Self::Item) -> Fut,
+    {
+        TakeWhile::new(self, f)
+    }
+
+    fn skip_while<Fut, F>(self, f: F) -> SkipWhile<Self, Fut, F>
+    where
+        Self: Sized,
+        Fut: AbortSafeFuture<Output = bool>,
+        F: FnMut(&Self::Item) -> Fut,
+    {
+        SkipWhile::new(self, f)
+    }
+
+    fn count(self) -> Count<Self>
+    where
+        Self: Sized,
+    {
+        Count::new(self)
+    }
+
+    fn concat(self) -> Concat<Self>
+    where
+        Self: Sized,
+        Self::Item: Default + Extend<<Self::Item as IntoIterator>::Item> + IntoIterator,
+    {
+        Concat::new(self)
+    }
+
+    fn unzip<A, B>(self) -> Unzip<Self, A, B>
+    where
+        Self: AbortSafeStream<Item = (A, B)> + Sized,
+    {
+        Unzip::new(self)
+    }
+}
+
+impl<St: AbortSafeStream + ?Sized> AbortSafeStreamExt for St {}
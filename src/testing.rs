@@ -0,0 +1,279 @@
+//! 测试用的工具，可以一次只`poll`一步，检查每一步的`Pending`/`Ready`以及唤醒的次数。
+//!
+//! ```
+//! #![feature(arbitrary_self_types)]
+//! use std::mem::ManuallyDrop;
+//! use std::pin::Pin;
+//! use std::sync::atomic::Ordering;
+//! use std::task::Poll;
+//! use abort_safe_future::{poll_fn, AbortSafeFutureExt};
+//! use abort_safe_future::testing::{counting_waker, poll_once_with};
+//!
+//! let mut first = true;
+//! let fut = poll_fn(move |cx| {
+//!     if first {
+//!         first = false;
+//!         cx.waker().wake_by_ref();
+//!         return Poll::Pending;
+//!     }
+//!     Poll::Ready(1)
+//! })
+//! .then(|x| abort_safe_future::ready(x + 1));
+//! let mut fut = Box::pin(ManuallyDrop::new(fut));
+//!
+//! let (waker, wakes) = counting_waker();
+//! assert_eq!(poll_once_with(fut.as_mut(), &waker), Poll::Pending);
+//! assert_eq!(wakes.load(Ordering::SeqCst), 1);
+//! assert_eq!(poll_once_with(fut.as_mut(), &waker), Poll::Ready(2));
+//! assert_eq!(wakes.load(Ordering::SeqCst), 1);
+//! ```
+
+use std::cell::RefCell;
+use std::mem::ManuallyDrop;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::task::{ready, Context, Poll, Wake, Waker};
+use std::thread;
+use pin_project::pin_project;
+use crate::future::AbortSafeFuture;
+use crate::helpers::pin_manually_drop_as_mut;
+
+struct CountingWaker(Arc<AtomicUsize>);
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// 什么都不做的waker
+pub fn noop_waker() -> Waker {
+    futures::task::noop_waker()
+}
+
+/// 记录被唤醒次数的waker
+pub fn counting_waker() -> (Waker, Arc<AtomicUsize>) {
+    let count = Arc::new(AtomicUsize::new(0));
+    (Waker::from(Arc::new(CountingWaker(count.clone()))), count)
+}
+
+/// 用`noop_waker`对`fut`调用一次`poll`
+pub fn poll_once<F>(fut: Pin<&mut ManuallyDrop<F>>) -> Poll<F::Output>
+where
+    F: AbortSafeFuture + ?Sized,
+{
+    poll_once_with(fut, &noop_waker())
+}
+
+/// 用给定的waker对`fut`调用一次`poll`
+pub fn poll_once_with<F>(fut: Pin<&mut ManuallyDrop<F>>, waker: &Waker) -> Poll<F::Output>
+where
+    F: AbortSafeFuture + ?Sized,
+{
+    fut.poll(&mut Context::from_waker(waker))
+}
+
+/// 用`noop_waker`对`fut`调用一次`poll_cancel`
+pub fn poll_cancel_once<F>(fut: Pin<&mut ManuallyDrop<F>>) -> Poll<()>
+where
+    F: AbortSafeFuture + ?Sized,
+{
+    fut.poll_cancel(&mut Context::from_waker(&noop_waker()))
+}
+
+/// 包装`fut`，检查它最终有没有被回收，即`poll`或者`poll_cancel`有没有返回过`Poll::Ready`。
+///
+/// future本身被`ManuallyDrop`包住，不会被析构，所以检查放在返回的`Finalized`里：
+/// `Finalized`被析构时，如果`fut`还没有被回收，会触发`debug_assert!`。
+///
+/// ```should_panic
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use std::pin::Pin;
+/// use abort_safe_future::testing::{checked, poll_once};
+///
+/// let (fut, finalized) = checked(abort_safe_future::pending::<()>());
+/// let mut fut = ManuallyDrop::new(fut);
+/// assert!(poll_once(Pin::new(&mut fut)).is_pending());
+/// // `poll_cancel` is never called
+/// drop(finalized);
+/// ```
+pub fn checked<Fut: AbortSafeFuture>(fut: Fut) -> (Checked<Fut>, Finalized) {
+    let finalized = Arc::new(AtomicBool::new(false));
+    let checked = Checked {
+        inner: ManuallyDrop::new(fut),
+        finalized: finalized.clone(),
+    };
+    (checked, Finalized(finalized))
+}
+
+#[pin_project]
+pub struct Checked<Fut> {
+    #[pin]
+    inner: ManuallyDrop<Fut>,
+    finalized: Arc<AtomicBool>,
+}
+
+impl<Fut: AbortSafeFuture> AbortSafeFuture for Checked<Fut> {
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        let output = ready!(this.inner.poll(cx));
+        this.finalized.store(true, Ordering::SeqCst);
+        Poll::Ready(output)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        ready!(this.inner.poll_cancel(cx));
+        this.finalized.store(true, Ordering::SeqCst);
+        Poll::Ready(())
+    }
+}
+
+/// `checked`返回的检查器
+#[derive(Debug)]
+pub struct Finalized(Arc<AtomicBool>);
+
+impl Finalized {
+    /// future是否已经被回收
+    pub fn is_finalized(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Finalized {
+    fn drop(&mut self) {
+        // do not turn an unrelated panic into an abort
+        if !thread::panicking() {
+            debug_assert!(self.is_finalized(), "future was not finalized: neither `poll` nor `poll_cancel` returned `Poll::Ready`");
+        }
+    }
+}
+
+/// 记录回收的顺序，用于测试组合子回收子future的顺序。
+///
+/// ```
+/// use abort_safe_future::{ready, AbortSafeFutureExt};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::testing::DropOrderRecorder;
+///
+/// let recorder = DropOrderRecorder::new();
+/// let fut = recorder.wrap("first", ready(1)).then(|x| recorder.wrap("second", ready(x + 1)));
+/// assert_eq!(block_on(fut), 2);
+/// assert_eq!(recorder.events(), ["first", "second"]);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct DropOrderRecorder {
+    events: Rc<RefCell<Vec<&'static str>>>,
+}
+
+impl DropOrderRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 记录一个事件
+    pub fn record(&self, name: &'static str) {
+        self.events.borrow_mut().push(name);
+    }
+
+    /// 到目前为止记录的所有事件
+    pub fn events(&self) -> Vec<&'static str> {
+        self.events.borrow().clone()
+    }
+
+    /// 包装`fut`，在它被回收时记录`name`，即`poll`或者`poll_cancel`第一次返回`Poll::Ready`时
+    pub fn wrap<Fut: AbortSafeFuture>(&self, name: &'static str, fut: Fut) -> Recorded<Fut> {
+        Recorded {
+            inner: ManuallyDrop::new(fut),
+            name,
+            recorder: Some(self.clone()),
+        }
+    }
+}
+
+#[pin_project]
+pub struct Recorded<Fut> {
+    #[pin]
+    inner: ManuallyDrop<Fut>,
+    name: &'static str,
+    recorder: Option<DropOrderRecorder>,
+}
+
+impl<Fut: AbortSafeFuture> AbortSafeFuture for Recorded<Fut> {
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        let output = ready!(this.inner.poll(cx));
+        if let Some(recorder) = this.recorder.take() {
+            recorder.record(this.name);
+        }
+        Poll::Ready(output)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(recorder) = this.recorder.as_ref() {
+            ready!(this.inner.poll_cancel(cx));
+            recorder.record(this.name);
+            *this.recorder = None;
+        }
+
+        Poll::Ready(())
+    }
+}
+
+/// 检查`fut`已经被回收：连续调用三次`poll_cancel`都必须返回`Poll::Ready`
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::mem::ManuallyDrop;
+/// use abort_safe_future::{join, pending, ready, select, AbortSafeFuture, AbortSafeFutureExt};
+/// use abort_safe_future::combinator::Compat;
+/// use abort_safe_future::testing::{assert_cancel_idempotent, poll_cancel_once, poll_once};
+///
+/// fn after_completion<F: AbortSafeFuture>(fut: F) {
+///     let mut fut = Box::pin(ManuallyDrop::new(fut));
+///     assert!(poll_once(fut.as_mut()).is_ready());
+///     assert_cancel_idempotent(fut.as_mut());
+/// }
+///
+/// fn after_cancel<F: AbortSafeFuture>(fut: F) {
+///     let mut fut = Box::pin(ManuallyDrop::new(fut));
+///     let _ = poll_once(fut.as_mut());
+///     assert!(poll_cancel_once(fut.as_mut()).is_ready());
+///     assert_cancel_idempotent(fut.as_mut());
+/// }
+///
+/// after_completion(Compat::new(async { 1 }));
+/// after_completion(ready(1).then(|x| ready(x + 1)));
+/// after_completion(ready(1).map(|x| x + 1));
+/// after_completion(join(ready(1), ready(2)));
+/// after_completion(select(ready(1), pending::<u32>()));
+///
+/// after_cancel(Compat::new(async { 1 }));
+/// after_cancel(pending::<u32>().then(|x| ready(x + 1)));
+/// after_cancel(pending::<u32>().map(|x| x + 1));
+/// after_cancel(join(ready(1), pending::<u32>()));
+/// after_cancel(select(pending::<u32>(), pending::<u32>()));
+/// ```
+pub fn assert_cancel_idempotent<F>(mut fut: Pin<&mut ManuallyDrop<F>>)
+where
+    F: AbortSafeFuture + ?Sized,
+{
+    for _ in 0..3 {
+        assert!(
+            poll_cancel_once(fut.as_mut()).is_ready(),
+            "`poll_cancel` returned `Poll::Pending` after the future was finalized"
+        );
+    }
+}
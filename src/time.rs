@@ -0,0 +1,373 @@
+use core::task::Context;
+use core::task::Poll;
+use core::pin::Pin;
+use core::mem::ManuallyDrop;
+
+use std::error::Error;
+use std::fmt;
+use std::task::ready;
+use std::time::Duration;
+use pin_project::pin_project;
+use crate::combinator::{Either, Select};
+use crate::future::AbortSafeFuture;
+use crate::helpers::pin_manually_drop_as_mut;
+
+
+/// 计时器。crate本身没有reactor，由使用者提供具体的实现，
+/// `executor::ThreadTimer`是基于后台线程的实现。
+///
+/// 也就是异步sleep的抽象：`sleep`需要用到计时器的状态，所以是`&self`的方法，而不是单独的静态函数。
+pub trait Timer {
+    /// 经过一段时间之后完成的future
+    type Sleep: AbortSafeFuture<Output = ()>;
+
+    fn sleep(&self, dur: Duration) -> Self::Sleep;
+}
+
+/// `timeout`超时时返回的错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed(());
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("deadline has elapsed")
+    }
+}
+
+impl Error for Elapsed {}
+
+/// 在`dur`之内完成的话返回`Ok`，否则取消`fut`并返回`Err(Elapsed)`
+///
+/// ```
+/// use std::task::Poll;
+/// use std::time::Duration;
+/// use abort_safe_future::{pending, poll_fn, AbortSafeFutureExt, BoxAbortSafeFuture};
+/// use abort_safe_future::executor::block_on;
+/// use abort_safe_future::testing::checked;
+/// use abort_safe_future::time::{timeout, Timer};
+///
+/// // fires after `polls` polls, whatever the duration
+/// struct FakeTimer {
+///     polls: u32,
+/// }
+///
+/// impl Timer for FakeTimer {
+///     type Sleep = BoxAbortSafeFuture<'static, ()>;
+///
+///     fn sleep(&self, _dur: Duration) -> Self::Sleep {
+///         let mut left = self.polls;
+///         poll_fn(move |cx| {
+///             if left == 0 {
+///                 return Poll::Ready(());
+///             }
+///             left -= 1;
+///             cx.waker().wake_by_ref();
+///             Poll::Pending
+///         })
+///         .boxed()
+///     }
+/// }
+///
+/// let (inner, finalized) = checked(pending::<u32>());
+/// let fut = timeout(&FakeTimer { polls: 3 }, inner, Duration::from_secs(1));
+/// assert!(block_on(fut).is_err());
+/// assert!(finalized.is_finalized());
+/// ```
+pub fn timeout<T, Fut>(timer: &T, fut: Fut, dur: Duration) -> Timeout<Fut, T::Sleep>
+where
+    T: Timer,
+    Fut: AbortSafeFuture,
+{
+    Timeout::new(fut, timer.sleep(dur))
+}
+
+#[pin_project]
+pub struct Timeout<Fut: AbortSafeFuture, S: AbortSafeFuture<Output = ()>> {
+    #[pin]
+    inner: ManuallyDrop<Select<Fut, S>>,
+}
+
+impl<Fut: AbortSafeFuture, S: AbortSafeFuture<Output = ()>> Timeout<Fut, S> {
+    pub fn new(fut: Fut, sleep: S) -> Self {
+        Self {
+            inner: ManuallyDrop::new(Select::new(fut, sleep)),
+        }
+    }
+}
+
+impl<Fut, S> AbortSafeFuture for Timeout<Fut, S>
+where
+    Fut: AbortSafeFuture,
+    S: AbortSafeFuture<Output = ()>,
+{
+    type Output = Result<Fut::Output, Elapsed>;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        // `Select` cancels the loser before it completes
+        match ready!(this.inner.poll(cx)) {
+            Either::Left(output) => Poll::Ready(Ok(output)),
+            Either::Right(()) => Poll::Ready(Err(Elapsed(()))),
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = pin_manually_drop_as_mut(&mut self).project();
+        this.inner.poll_cancel(cx)
+    }
+}
+
+/// 等待`dur`之后才开始`poll` `fut`
+///
+/// ```
+/// #![feature(arbitrary_self_types)]
+/// use std::cell::Cell;
+/// use std::mem::ManuallyDrop;
+/// use std::task::Poll;
+/// use std::time::{Duration, Instant};
+/// use abort_safe_future::poll_fn;
+/// use abort_safe_future::executor::{block_on_boxed, ThreadTimer};
+/// use abort_safe_future::testing::poll_once;
+/// use abort_safe_future::time::delay;
+///
+/// let timer = ThreadTimer::new();
+/// let polls = Cell::new(0);
+/// let start = Instant::now();
+/// let mut fut = Box::pin(ManuallyDrop::new(delay(&timer, poll_fn(|_| {
+///     polls.set(polls.get() + 1);
+///     Poll::Ready(())
+/// }), Duration::from_millis(20))));
+///
+/// assert!(poll_once(fut.as_mut()).is_pending());
+/// assert_eq!(polls.get(), 0);
+/// block_on_boxed(fut);
+/// assert_eq!(polls.get(), 1);
+/// assert!(start.elapsed() >= Duration::from_millis(20));
+/// ```
+pub fn delay<T, Fut>(timer: &T, fut: Fut, dur: Duration) -> Delay<Fut, T::Sleep>
+where
+    T: Timer,
+    Fut: AbortSafeFuture,
+{
+    Delay::new(fut, timer.sleep(dur))
+}
+
+#[pin_project]
+pub struct Delay<Fut, S> {
+    #[pin]
+    sleep: Option<ManuallyDrop<S>>,
+    #[pin]
+    fut: Option<ManuallyDrop<Fut>>,
+}
+
+impl<Fut, S> Delay<Fut, S> {
+    pub fn new(fut: Fut, sleep: S) -> Self {
+        Self {
+            sleep: Some(ManuallyDrop::new(sleep)),
+            fut: Some(ManuallyDrop::new(fut)),
+        }
+    }
+}
+
+impl<Fut, S> AbortSafeFuture for Delay<Fut, S>
+where
+    Fut: AbortSafeFuture,
+    S: AbortSafeFuture<Output = ()>,
+{
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        if let Some(sleep) = this.sleep.as_mut().as_pin_mut() {
+            ready!(sleep.poll(cx));
+            this.sleep.set(None);
+        }
+
+        let fut = match this.fut.as_mut().as_pin_mut() {
+            Some(fut) => fut,
+            None => panic!("Delay::poll called after completion or after cancel"),
+        };
+
+        let output = ready!(fut.poll(cx));
+        this.fut.set(None);
+        Poll::Ready(output)
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        if let Some(sleep) = this.sleep.as_mut().as_pin_mut() {
+            if sleep.poll_cancel(cx).is_ready() {
+                this.sleep.set(None);
+            }
+        }
+
+        // `fut` is owned even if it has never been polled
+        if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+            if fut.poll_cancel(cx).is_ready() {
+                this.fut.set(None);
+            }
+        }
+
+        if this.sleep.is_some() || this.fut.is_some() {
+            return Poll::Pending;
+        }
+
+        Poll::Ready(())
+    }
+}
+
+/// `retry`的重试策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: usize,
+    backoff: Option<Duration>,
+}
+
+impl RetryPolicy {
+    /// 最多执行`max_attempts`次，包括第一次
+    pub fn new(max_attempts: usize) -> Self {
+        assert!(max_attempts > 0, "RetryPolicy needs at least one attempt");
+        Self {
+            max_attempts,
+            backoff: None,
+        }
+    }
+
+    /// 每次失败之后，等待`dur`再重试
+    pub fn backoff(mut self, dur: Duration) -> Self {
+        self.backoff = Some(dur);
+        self
+    }
+}
+
+/// 执行`f`返回的future，结果为`Err`时按照`policy`重新调用`f`构造新的future，
+/// 直到成功或者达到最大次数，返回最后一次的结果。
+///
+/// 失败的future完成时已经回收了自己的资源。取消时，正在执行的future或者正在等待的sleep会被取消。
+///
+/// ```
+/// use std::time::Duration;
+/// use abort_safe_future::executor::{block_on, ThreadTimer};
+/// use abort_safe_future::testing::checked;
+/// use abort_safe_future::time::{retry, RetryPolicy};
+///
+/// let mut attempts = 0;
+/// let mut checks = Vec::new();
+/// let fut = retry(
+///     ThreadTimer::new(),
+///     || {
+///         attempts += 1;
+///         let result = if attempts < 3 { Err(attempts) } else { Ok(attempts) };
+///         let (fut, finalized) = checked(abort_safe_future::ready(result));
+///         checks.push(finalized);
+///         fut
+///     },
+///     RetryPolicy::new(5).backoff(Duration::from_millis(1)),
+/// );
+///
+/// assert_eq!(block_on(fut), Ok(3));
+/// assert_eq!(checks.len(), 3);
+/// assert!(checks.iter().all(|finalized| finalized.is_finalized()));
+/// ```
+pub fn retry<T, F, Fut, R, E>(timer: T, f: F, policy: RetryPolicy) -> Retry<T, F, Fut>
+where
+    T: Timer,
+    F: FnMut() -> Fut,
+    Fut: AbortSafeFuture<Output = Result<R, E>>,
+{
+    Retry::new(timer, f, policy)
+}
+
+#[pin_project]
+pub struct Retry<T: Timer, F, Fut> {
+    timer: Option<T>,
+    f: Option<F>,
+    policy: RetryPolicy,
+    attempts: usize,
+    #[pin]
+    fut: Option<ManuallyDrop<Fut>>,
+    #[pin]
+    sleep: Option<ManuallyDrop<T::Sleep>>,
+}
+
+impl<T: Timer, F, Fut> Retry<T, F, Fut> {
+    pub fn new(timer: T, f: F, policy: RetryPolicy) -> Self {
+        Self {
+            timer: Some(timer),
+            f: Some(f),
+            policy,
+            attempts: 0,
+            fut: None,
+            sleep: None,
+        }
+    }
+}
+
+impl<T, F, Fut, R, E> AbortSafeFuture for Retry<T, F, Fut>
+where
+    T: Timer,
+    F: FnMut() -> Fut,
+    Fut: AbortSafeFuture<Output = Result<R, E>>,
+{
+    type Output = Result<R, E>;
+
+    fn poll(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+        loop {
+            if let Some(sleep) = this.sleep.as_mut().as_pin_mut() {
+                ready!(sleep.poll(cx));
+                this.sleep.set(None);
+            }
+
+            if this.fut.is_none() {
+                let f = match this.f.as_mut() {
+                    Some(f) => f,
+                    None => panic!("Retry::poll called after completion or after cancel"),
+                };
+                this.fut.set(Some(ManuallyDrop::new(f())));
+                *this.attempts += 1;
+            }
+
+            let result = ready!(this.fut.as_mut().as_pin_mut().unwrap().poll(cx));
+            this.fut.set(None);
+
+            if result.is_ok() || *this.attempts >= this.policy.max_attempts {
+                // drop closure and timer
+                *this.f = None;
+                *this.timer = None;
+                return Poll::Ready(result);
+            }
+
+            if let Some(backoff) = this.policy.backoff {
+                let sleep = this.timer.as_ref().unwrap().sleep(backoff);
+                this.sleep.set(Some(ManuallyDrop::new(sleep)));
+            }
+        }
+    }
+
+    fn poll_cancel(mut self: Pin<&mut ManuallyDrop<Self>>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = pin_manually_drop_as_mut(&mut self).project();
+
+        if let Some(fut) = this.fut.as_mut().as_pin_mut() {
+            if fut.poll_cancel(cx).is_ready() {
+                this.fut.set(None);
+            }
+        }
+
+        if let Some(sleep) = this.sleep.as_mut().as_pin_mut() {
+            if sleep.poll_cancel(cx).is_ready() {
+                this.sleep.set(None);
+            }
+        }
+
+        if this.fut.is_some() || this.sleep.is_some() {
+            return Poll::Pending;
+        }
+
+        // drop closure and timer
+        *this.f = None;
+        *this.timer = None;
+        Poll::Ready(())
+    }
+}
@@ -0,0 +1,53 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::mem::ManuallyDrop;
+use abort_safe_future::combinator::Compat;
+use abort_safe_future::executor::{block_on, block_on_boxed};
+
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // `try_with` because the thread local may already be gone while the thread exits
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let output = f();
+    (output, ALLOCATIONS.with(Cell::get) - before)
+}
+
+#[test]
+fn block_on_pins_the_future_on_the_stack() {
+    // warm up the thread handle used by the waker
+    block_on(Compat::new(async {}));
+
+    let (output, small) = allocations(|| block_on(Compat::new(async { 1 })));
+    assert_eq!(output, 1);
+
+    let big = [7u8; 4096];
+    let (output, large) = allocations(|| block_on(Compat::new(async move { big.iter().map(|&b| b as u32).sum::<u32>() })));
+    assert_eq!(output, 7 * 4096);
+
+    // only the waker is allocated, however big the future is
+    assert_eq!(small, large);
+    assert_eq!(small, 1);
+
+    let (output, boxed) = allocations(|| block_on_boxed(Box::pin(ManuallyDrop::new(Compat::new(async { 1 })))));
+    assert_eq!(output, 1);
+    assert_eq!(boxed, small + 1);
+}